@@ -0,0 +1,45 @@
+-- Tables the bot writes the answers to. `migrate` runs every statement in order,
+-- all of them can be run again, so the file works for new and existing databases.
+
+CREATE TABLE IF NOT EXISTS tg_room_bot
+(
+    room_id String,
+    creator_id Int32,
+    visitor_id Int32,
+    pack String,
+    created_at UInt32,
+    idx UInt16,
+    creator_importance Int8,
+    creator_evaluation Int8,
+    visitor_importance Int8,
+    visitor_evaluation Int8,
+    creator_ready_at UInt32,
+    visitor_ready_at UInt32
+)
+ENGINE = MergeTree
+ORDER BY (room_id, idx);
+
+-- guess rooms
+ALTER TABLE tg_room_bot ADD COLUMN IF NOT EXISTS mode String DEFAULT 'classic' AFTER pack;
+ALTER TABLE tg_room_bot ADD COLUMN IF NOT EXISTS creator_guess Int8 DEFAULT -1 AFTER creator_evaluation;
+ALTER TABLE tg_room_bot ADD COLUMN IF NOT EXISTS visitor_guess Int8 DEFAULT -1 AFTER visitor_evaluation;
+
+-- discussion timers
+ALTER TABLE tg_room_bot ADD COLUMN IF NOT EXISTS question_started_at UInt32 DEFAULT 0;
+ALTER TABLE tg_room_bot ADD COLUMN IF NOT EXISTS timer UInt16 DEFAULT 0;
+
+-- solo runs
+CREATE TABLE IF NOT EXISTS tg_solo_runs
+(
+    run_id String,
+    user_id Int32,
+    pack String,
+    created_at UInt32,
+    idx UInt16,
+    position UInt16,
+    importance Int8,
+    note String,
+    ready_at UInt32
+)
+ENGINE = MergeTree
+ORDER BY (run_id, idx);
@@ -0,0 +1,6 @@
+-- Joins a visitor to a room if nobody has joined it yet.
+-- KEYS[1] - room, ARGV[1] - user id. Returns 1 if the user has joined.
+if redis.call('EXISTS', KEYS[1]) == 0 then
+    return 0
+end
+return redis.call('HSETNX', KEYS[1], 'visitor_id', ARGV[1])
@@ -0,0 +1,12 @@
+-- Marks that a role is done with the current step, e.g. is ready for the next question.
+-- KEYS[1] - room, ARGV[1] - field of the role, ARGV[2] - time,
+-- ARGV[3] - field of the partner, empty if the room has no partner.
+-- Returns 1 only to the call which completes the step, so that the room moves on once.
+if redis.call('HSETNX', KEYS[1], ARGV[1], ARGV[2]) == 0 then
+    return 0
+end
+
+if ARGV[3] == '' or redis.call('HEXISTS', KEYS[1], ARGV[3]) == 1 then
+    return 1
+end
+return 0
@@ -0,0 +1,24 @@
+-- Sets a rating of a role and tells whether it was the last one the role had to set.
+-- KEYS[1] - room, ARGV[1] - rating field, ARGV[2] - value,
+-- ARGV[3] - "1" if the rating can't be changed once set, ARGV[4..] - all rating fields of the role.
+-- Returns -1 if the rating is locked, 1 if the role has just set all its ratings, otherwise 0.
+local function has_all()
+    for i = 4, #ARGV do
+        if redis.call('HEXISTS', KEYS[1], ARGV[i]) == 0 then
+            return false
+        end
+    end
+    return true
+end
+
+if ARGV[3] == '1' and redis.call('HEXISTS', KEYS[1], ARGV[1]) == 1 then
+    return -1
+end
+
+local had_all = has_all()
+redis.call('HSET', KEYS[1], ARGV[1], ARGV[2])
+
+if not had_all and has_all() then
+    return 1
+end
+return 0
@@ -0,0 +1,35 @@
+use crate::error::BotResult;
+use crate::metrics::CLICKHOUSE_DURATION;
+
+use reqwest::Client;
+use std::time::Instant;
+
+/// Runs a ClickHouse query over HTTP and returns the response body.
+/// `name` labels the query in the metrics.
+pub async fn query(
+    client: &Client,
+    ch_url: &String,
+    query: String,
+    name: &str,
+) -> BotResult<Vec<u8>> {
+    let started = Instant::now();
+    let result = execute(client, ch_url, query).await;
+    let outcome = if result.is_ok() { "ok" } else { "error" };
+
+    CLICKHOUSE_DURATION
+        .with_label_values(&[name, outcome])
+        .observe(started.elapsed().as_secs_f64());
+
+    result
+}
+
+async fn execute(client: &Client, ch_url: &String, query: String) -> BotResult<Vec<u8>> {
+    let response = client
+        .post(ch_url)
+        .body(query)
+        .send()
+        .await?
+        .error_for_status()?;
+
+    Ok(response.bytes().await?.to_vec())
+}
@@ -0,0 +1,196 @@
+use crate::analytics;
+use crate::bot::constants::*;
+use crate::bot::locale::Locale;
+use crate::bot::order::QuestionOrder;
+use crate::bot::pack::Pack;
+use crate::bot::room::*;
+use crate::error::BotResult;
+use crate::metrics::QUESTIONS_ANSWERED;
+use crate::settings::settings;
+use crate::storage::RedisConnection;
+use crate::telegram::messages::*;
+use crate::telegram::structures::*;
+use crate::ternary;
+use crate::tools::*;
+
+use redis::AsyncCommands;
+use reqwest::Client;
+use std::collections::HashMap;
+
+/// A room where each partner goes through the pack at their own pace.
+/// Ratings are kept per question until both partners finish, then written at once and reported.
+pub struct AsyncRoom;
+impl AsyncRoom {
+    fn key_answers_str(room_id: &str) -> String {
+        format!("room:{}:answers", room_id)
+    }
+
+    pub(crate) fn keys(room_id: &str) -> [String; 1] {
+        [AsyncRoom::key_answers_str(room_id)]
+    }
+
+    pub(crate) async fn advance(
+        user_id: i32,
+        user_room: &UserRoom,
+        redis: &mut RedisConnection,
+        client: &Client,
+        url: &str,
+        ch_url: &String,
+    ) -> BotResult<Option<OutgoingKeyboardMessage>> {
+        // a retry after a failed finish has no answers left to save
+        let idx = match AsyncRoom::answered_all(user_room, redis).await? {
+            Some(idx) => idx,
+            None => AsyncRoom::save_answers(user_room, redis).await?,
+        };
+
+        if let Some(question_message) = QuestionMessage::get(&user_room.id, idx, redis).await? {
+            question_message
+                .send(user_id, &user_room.id, redis, client, url)
+                .await?;
+
+            return Ok(None);
+        }
+
+        let partner_finished = user_room.mark_done("finished_at", true, redis).await?;
+        let users = Room::user_ids(&user_room.id, redis).await?;
+
+        if partner_finished {
+            if let Err(e) = AsyncRoom::write_data(&user_room.id, redis, client, ch_url).await {
+                user_room.undo_done("finished_at", redis).await?;
+                return Err(e);
+            }
+            send_question_messages(&users, idx, redis, client, url, &user_room.id, ch_url).await?;
+
+            return Ok(None);
+        }
+
+        Context::set_context(user_id, Context::WAITING_FOR_RESULTS, redis).await?;
+
+        let partners: Vec<i32> = users.into_iter().filter(|&x| x != user_id).collect();
+
+        for partner_id in Unreachable::reachable(&partners, redis).await? {
+            let partner_locale = Locale::get(partner_id, redis).await?;
+            send_message(
+                url,
+                &OutgoingKeyboardMessage::with_text(
+                    partner_id,
+                    partner_locale.text(Messages::PARTNER_FINISHED),
+                ),
+                client,
+            )
+            .await?;
+        }
+
+        Ok(Some(OutgoingKeyboardMessage::with_text(
+            user_id,
+            Locale::get(user_id, redis)
+                .await?
+                .text(Messages::WAITING_FOR_PARTNER_FINISH),
+        )))
+    }
+
+    /// Moves the ratings of the user's current question to the answers hash
+    /// and returns the number of the next question for the user.
+    /// The question number of the role if it has already gone through the whole order.
+    pub(crate) async fn answered_all(
+        user_room: &UserRoom,
+        redis: &mut RedisConnection,
+    ) -> redis::RedisResult<Option<u16>> {
+        let room_key = Room::key(&user_room.id, redis).await?;
+        let room: HashMap<String, String> = redis.hgetall(&room_key).await?;
+        let pack = room.get("pack").cloned().unwrap_or_default();
+        let pack_len = Pack::len(&pack, redis).await?;
+        let idx: u16 = get_parse_string_value(&room, &format!("{}_idx", user_room.role), 0);
+
+        Ok(ternary!(
+            idx >= QuestionOrder::length(&room, pack_len),
+            Some(idx),
+            None
+        ))
+    }
+
+    async fn save_answers(
+        user_room: &UserRoom,
+        redis: &mut RedisConnection,
+    ) -> redis::RedisResult<u16> {
+        let room_key = Room::key(&user_room.id, redis).await?;
+        let answers_key = AsyncRoom::key_answers_str(&user_room.id);
+        let idx_field = format!("{}_idx", user_room.role);
+        let idx: u16 = redis
+            .hget::<_, _, Option<u16>>(&room_key, &idx_field)
+            .await?
+            .unwrap_or(0);
+
+        let role_fields: Vec<String> = RoomMode::rating_fields(RoomMode::ASYNC)
+            .iter()
+            .map(|field| format!("{}_{}", user_room.role, field))
+            .collect();
+
+        let importance: Option<i64> = redis
+            .hget(&room_key, format!("{}_importance", user_room.role))
+            .await?;
+        if let Some(importance) = importance {
+            QuestionOrder::record_answer(&user_room.id, idx, importance, redis).await?;
+        }
+
+        for role_field in role_fields.iter() {
+            let value: Option<String> = redis.hget(&room_key, role_field).await?;
+
+            if let Some(value) = value {
+                let _: () = redis
+                    .hset(&answers_key, format!("{}:{}", idx, role_field), value)
+                    .await?;
+            }
+        }
+
+        let _: () = redis
+            .hset(
+                &answers_key,
+                format!("{}:{}_ready_at", idx, user_room.role),
+                current_time(),
+            )
+            .await?;
+        let _: () = redis.expire(&answers_key, settings().storage.ttl).await?;
+        let _: () = redis.hdel(&room_key, role_fields.as_slice()).await?;
+        QUESTIONS_ANSWERED.inc();
+
+        redis.hincr(&room_key, &idx_field, 1).await
+    }
+
+    pub(crate) async fn write_data(
+        room_id: &String,
+        redis: &mut RedisConnection,
+        client: &Client,
+        ch_url: &String,
+    ) -> BotResult<()> {
+        let room_key = Room::key(room_id, redis).await?;
+        let room: HashMap<String, String> = redis.hgetall(&room_key).await?;
+        let answers: HashMap<String, String> =
+            redis.hgetall(AsyncRoom::key_answers_str(room_id)).await?;
+        let questions: u16 = get_parse_string_value(&room, "creator_idx", 0)
+            .max(get_parse_string_value(&room, "visitor_idx", 0));
+
+        let rows: Vec<String> = (0..questions)
+            .map(|idx| {
+                let prefix = format!("{}:", idx);
+                let mut question = room.clone();
+
+                question.insert("idx".to_string(), idx.to_string());
+                question.extend(answers.iter().filter_map(|(field, value)| {
+                    field
+                        .strip_prefix(&prefix)
+                        .map(|field| (field.to_string(), value.to_string()))
+                }));
+
+                Room::row_values(room_id, &question)
+            })
+            .collect();
+
+        if !rows.is_empty() {
+            let query = Room::insert_query(&rows);
+            analytics::query(client, ch_url, query, "async_insert").await?;
+        }
+
+        Ok(())
+    }
+}
@@ -1,58 +1,126 @@
-pub(crate) const IMPORTANCE_EMOJIS: [&str; 5] = [" 0️", "✔️", "❗", "‼️", "️🔥"];
-pub(crate) const EVALUATION_EMOJIS: [&str; 5] = ["😡", "🙁", "😐", "😊", "️😀"];
+use crate::bot::locale::Locale;
 
 pub(crate) struct RedisKeys;
 impl RedisKeys {
     pub const PACKS: &'static str = "packs";
     pub const LATEST_MESSAGE: &'static str = "latest_message";
+    pub const TIMERS: &'static str = "timers";
+    pub const ROOM_HANDLES: &'static str = "room_handles";
+    pub const SCHEMA_VERSION: &'static str = "schema_version";
 }
 
+/// Keys of the bot texts in the `locales` catalogs, see `Locale::text`.
 pub struct Messages;
 impl Messages {
-    pub const WELCOME: &'static str = r#"👋Привет! Рад видеть тебя.
-Нажми "Создать", чтобы выбрать набор вопросов и запустить комнату.
-Нажми "Вступить", чтобы вставить получанный от партнера ID комнаты и начать общение.
-"#;
-    pub const WAITING_FOR_PARTNER: &'static str = "Ждем, пока партнер зайдет в комнату.";
-    pub const WAITING_FOR_PARTNER_EVAL: &'static str = "Ожидание оценок партнера";
-    pub const INSERT_ROOM_ID: &'static str = "Введи ID комнаты";
-    pub const NO_ROOM_ID_IN_MESSAGE: &'static str = "Не могу найти ID в тексте сообщения.";
-    pub const WRONG_ROOM_ID: &'static str = "Неверный ID комнаты, попробуй еще.";
-    pub const CHOOSE_PACK: &'static str = "Выбери набор";
-    pub const READY_FOR_NEXT: &'static str = "Скажи, когда будешь готов продолжить";
-    pub const EVALUATING_RESULTS: &'static str =
-        "Это был последний вопрос! Подожди, пока подвожу итоги...";
-    pub const WAIT_A_MOMENT: &'static str = "Подожди минутку...";
-    pub const ANSWER_IMPORTANCE: &'static str = "Насколько тебе важен ответ?";
-    pub const ANSWER_EVALUATION: &'static str = "Как тебе ответ партнера?";
-    pub const ALL_QUESTIONS_NON_IMPORTANT: &'static str = "Вы оба посчитали вопросы неважными!";
-    pub const HELP: &'static str = r#"Бот, который присылает вопросы для обсуждения.
-
-Нажми "Создать", чтобы выбрать набор вопросов и запустить комнату. Бот пришлет ID комнаты: его нужно отправить партнеру.
-Нажми "Вступить", чтобы вставить получанный от партнера ID комнаты и начать общение.
-
-Бот будет присылать вопросы для обсуждения по одному. Общайтесь, оценивайте важность ответа партнера и то, насколько ответ понравился.
-Когда будут оценены все вопросы, бот пришлет маленький отчет, в котором расскажет, как вы оценили друг друга.
-"#;
-
-    pub const ERROR: &'static str = "Ошибка, попробуй ещё.";
-    pub const ERROR_PACK_DOES_NOT_EXIST: &'static str =
-        "Такого набора не существует, попробуй выбрать кнопкой.";
-    pub const ERROR_INTERNAL: &'static str = "Ошибка бота, попробуй ещё раз немного позже.";
-    pub const ERROR_UNKNOWN_COMMAND: &'static str =
-        "Неизвестная команда бота. Попробуй выбрать из предложенного списка.";
+    pub const WELCOME: &'static str = "messages.welcome";
+    pub const WAITING_FOR_PARTNER: &'static str = "messages.waiting_for_partner";
+    pub const ASYNC_START: &'static str = "messages.async_start";
+    pub const ROOM_ID: &'static str = "messages.room_id";
+    pub const WAITING_FOR_PARTNER_EVAL: &'static str = "messages.waiting_for_partner_eval";
+    pub const INSERT_ROOM_ID: &'static str = "messages.insert_room_id";
+    pub const NO_ROOM_ID_IN_MESSAGE: &'static str = "messages.no_room_id_in_message";
+    pub const WRONG_ROOM_ID: &'static str = "messages.wrong_room_id";
+    pub const ROOM_TAKEN: &'static str = "messages.room_taken";
+    pub const CHOOSE_PACK: &'static str = "messages.choose_pack";
+    pub const CHOOSE_PACK_LANGUAGE: &'static str = "messages.choose_pack_language";
+    pub const CHOOSE_ORDER: &'static str = "messages.choose_order";
+    pub const CHOOSE_TIMER: &'static str = "messages.choose_timer";
+    pub const TIME_LEFT: &'static str = "messages.time_left";
+    pub const TIME_IS_UP: &'static str = "messages.time_is_up";
+    pub const RATE_AFTER_TIMER: &'static str = "messages.rate_after_timer";
+    pub const PARTNER_ANSWERING: &'static str = "messages.partner_answering";
+    pub const PARTNER_RATED: &'static str = "messages.partner_rated";
+    pub const PARTNER_READY: &'static str = "messages.partner_ready";
+    pub const PARTNER_RATED_NOTIFICATION: &'static str = "messages.partner_rated_notification";
+    pub const EVALUATING_RESULTS: &'static str = "messages.evaluating_results";
+    pub const WAIT_A_MOMENT: &'static str = "messages.wait_a_moment";
+    pub const QUESTION_HEADER: &'static str = "messages.question_header";
+    pub const ANSWER_IMPORTANCE: &'static str = "messages.answer_importance";
+    pub const ANSWER_EVALUATION: &'static str = "messages.answer_evaluation";
+    pub const ANSWER_GUESS: &'static str = "messages.answer_guess";
+    pub const ANSWER_SELF_EVALUATION: &'static str = "messages.answer_self_evaluation";
+    pub const ANSWER_SELF_IMPORTANCE: &'static str = "messages.answer_self_importance";
+    pub const CALLBACK_RATING: &'static str = "messages.callback_rating";
+    pub const CALLBACK_GUESS: &'static str = "messages.callback_guess";
+    pub const CALLBACK_OUTDATED: &'static str = "messages.callback_outdated";
+    pub const CALLBACK_INVALID: &'static str = "messages.callback_invalid";
+    pub const PARTNER_FINISHED: &'static str = "messages.partner_finished";
+    pub const WAITING_FOR_PARTNER_FINISH: &'static str = "messages.waiting_for_partner_finish";
+    pub const PARTNER_UNREACHABLE: &'static str = "messages.partner_unreachable";
+    pub const PARTNER_UNREACHABLE_FINISHED: &'static str = "messages.partner_unreachable_finished";
+    pub const ROOM_LEFT: &'static str = "messages.room_left";
+    pub const SOLO_NOTE_HINT: &'static str = "messages.solo_note_hint";
+    pub const NOTE_SAVED: &'static str = "messages.note_saved";
+    pub const COMPARE_USAGE: &'static str = "messages.compare_usage";
+    pub const GUESS_ALREADY_SET: &'static str = "messages.guess_already_set";
+    pub const ALL_QUESTIONS_NON_IMPORTANT: &'static str = "messages.all_questions_non_important";
+    pub const LANGUAGE_USAGE: &'static str = "messages.language_usage";
+    pub const LANGUAGE_CHANGED: &'static str = "messages.language_changed";
+    pub const HELP: &'static str = "messages.help";
+
+    pub const ERROR: &'static str = "messages.error";
+    pub const ERROR_PACK_DOES_NOT_EXIST: &'static str = "messages.error_pack_does_not_exist";
+    pub const ERROR_PACK_LANGUAGE_DOES_NOT_EXIST: &'static str =
+        "messages.error_pack_language_does_not_exist";
+    pub const ERROR_SOLO_RUN_DOES_NOT_EXIST: &'static str =
+        "messages.error_solo_run_does_not_exist";
+    pub const ERROR_NO_OWN_SOLO_RUN: &'static str = "messages.error_no_own_solo_run";
+    pub const ERROR_TIMER_DOES_NOT_EXIST: &'static str = "messages.error_timer_does_not_exist";
+    pub const ERROR_ORDER_DOES_NOT_EXIST: &'static str = "messages.error_order_does_not_exist";
+    pub const ERROR_LANGUAGE_DOES_NOT_EXIST: &'static str =
+        "messages.error_language_does_not_exist";
+    pub const ERROR_INTERNAL: &'static str = "messages.error_internal";
+    pub const ERROR_ANALYTICS: &'static str = "messages.error_analytics";
+    pub const ERROR_STATE: &'static str = "messages.error_state";
+    pub const ERROR_UNKNOWN_COMMAND: &'static str = "messages.error_unknown_command";
 }
 
+/// Keys of the keyboard buttons in the `locales` catalogs.
 pub struct Keys;
 impl Keys {
-    pub const CREATE: &'static str = "🧩Сoздать";
-    pub const JOIN: &'static str = "🎟Вступить";
-    pub const READY: &'static str = "Готов!";
-
-    pub fn welcome() -> Vec<Vec<String>> {
-        vec![[Keys::CREATE, Keys::JOIN]
-            .iter()
-            .map(|&x| String::from(x))
-            .collect()]
+    pub const CREATE: &'static str = "keys.create";
+    pub const CREATE_GUESS: &'static str = "keys.create_guess";
+    pub const CREATE_SOLO: &'static str = "keys.create_solo";
+    pub const CREATE_ASYNC: &'static str = "keys.create_async";
+    pub const JOIN: &'static str = "keys.join";
+    pub const READY: &'static str = "keys.ready";
+    pub const ORDER_SEQUENTIAL: &'static str = "keys.order_sequential";
+    pub const ORDER_SHUFFLED: &'static str = "keys.order_shuffled";
+    pub const ORDER_QUICK: &'static str = "keys.order_quick";
+    pub const ORDER_ADAPTIVE: &'static str = "keys.order_adaptive";
+    pub const TIMER_NONE: &'static str = "keys.timer_none";
+    pub const TIMER_MINUTES: &'static str = "keys.timer_minutes";
+    pub const CONTINUE_ALONE: &'static str = "keys.continue_alone";
+    pub const LEAVE_ROOM: &'static str = "keys.leave_room";
+
+    // texts of the reply keyboard users may still have from earlier versions
+    const LEGACY: [(&'static str, &'static str); 2] =
+        [(Keys::CREATE, "🧩Сoздать"), (Keys::READY, "Готов!")];
+
+    /// Button key the typed text belongs to in any locale.
+    /// Buttons send callbacks, so typed text is only a fallback and should be matched generously.
+    pub fn find(text: &str, keys: &[&'static str]) -> Option<&'static str> {
+        keys.iter()
+            .find(|&&key| {
+                Locale::ALL.iter().any(|locale| locale.text(key) == text)
+                    || Keys::LEGACY.contains(&(key, text))
+            })
+            .copied()
     }
+
+    pub fn matches(text: Option<&String>, key: &'static str) -> bool {
+        text.and_then(|x| Keys::find(x, &[key])).is_some()
+    }
+}
+
+/// Keys of the report templates in the `locales` catalogs.
+pub struct Reports;
+impl Reports {
+    pub const ROOM: &'static str = "reports.room";
+    pub const ROOM_TIME: &'static str = "reports.room_time";
+    pub const GUESS: &'static str = "reports.guess";
+    pub const SOLO: &'static str = "reports.solo";
+    pub const SOLO_TOP: &'static str = "reports.solo_top";
+    pub const COMPARISON: &'static str = "reports.comparison";
+    pub const COMPARISON_DIVERGING: &'static str = "reports.comparison_diverging";
 }
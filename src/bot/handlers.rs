@@ -1,121 +1,267 @@
+use crate::bot::async_room::AsyncRoom;
 use crate::bot::constants::*;
+use crate::bot::locale::Locale;
+use crate::bot::order::QuestionOrder;
+use crate::bot::pack::Pack;
 use crate::bot::room::*;
+use crate::bot::solo::SoloRun;
+use crate::error::{BotError, BotResult};
+use crate::metrics::RoomEvent;
+use crate::settings::settings;
+use crate::storage::RedisConnection;
 use crate::telegram::messages::*;
 use crate::telegram::structures::*;
 
-use redis::Commands;
+use redis::AsyncCommands;
 use reqwest::Client;
+use std::collections::HashMap;
 use std::convert::TryInto;
 
 pub struct Handlers;
 impl Handlers {
-    pub(crate) fn join_existing(
+    pub(crate) async fn join_existing(
         user_id: i32,
-        redis: &mut redis::Connection,
+        locale: Locale,
+        redis: &mut RedisConnection,
     ) -> Result<Option<OutgoingKeyboardMessage>, redis::RedisError> {
-        let msg = OutgoingKeyboardMessage::join_room(user_id);
-        Context::set_context(user_id, Context::INSERT_ID, redis)?;
+        let msg = OutgoingKeyboardMessage::join_room(user_id, locale);
+        Context::set_context(user_id, Context::INSERT_ID, redis).await?;
 
         Ok(Some(msg))
     }
 
-    pub(crate) fn create(
+    pub(crate) async fn create(
         user_id: i32,
-        redis: &mut redis::Connection,
+        mode: &'static str,
+        locale: Locale,
+        redis: &mut RedisConnection,
     ) -> Result<Option<OutgoingKeyboardMessage>, redis::RedisError> {
-        let packs: Vec<String> = redis.smembers(RedisKeys::PACKS)?;
-        let msg = OutgoingKeyboardMessage::create_select_pack(user_id, packs);
-        Context::set_context(user_id, Context::SELECT_PACK, redis)?;
+        let packs = Pack::names(redis).await?;
+        Room::reset_draft(user_id, redis).await?;
+        Room::set_draft(user_id, "mode", mode, redis).await?;
+        let msg = OutgoingKeyboardMessage::create_select_pack(user_id, packs, locale);
+        Context::set_context(user_id, Context::SELECT_PACK, redis).await?;
 
         Ok(Some(msg))
     }
 
-    pub(crate) fn new_room(
+    pub(crate) async fn select_pack(
         user_id: i32,
-        message: &Option<TgMessage>,
-        redis: &mut redis::Connection,
+        pack_opt: Option<String>,
+        locale: Locale,
+        redis: &mut RedisConnection,
     ) -> Result<Option<OutgoingKeyboardMessage>, redis::RedisError> {
-        let pack_opt = message.as_ref().and_then(|x| x.text.as_ref());
-
-        if pack_opt.is_some() {
-            let pack = pack_opt.unwrap();
-            let is_existing_pack: bool = redis.sismember(RedisKeys::PACKS, pack)?;
+        if let Some(pack) = pack_opt {
+            let is_existing_pack: bool = redis.sismember(RedisKeys::PACKS, &pack).await?;
             if is_existing_pack {
-                let room_id = Room::create(user_id, &pack, redis)?;
-                let msg = OutgoingKeyboardMessage::room_id_message(user_id, &room_id);
-                Context::set_context(user_id, Context::WAITING_FOR_PARTNER, redis)?;
+                Room::set_draft(user_id, "pack", &pack, redis).await?;
+                let languages = Pack::languages(&pack, redis).await?;
 
-                Ok(Some(msg))
+                if languages.len() > 1 {
+                    Context::set_context(user_id, Context::SELECT_PACK_LANGUAGE, redis).await?;
+                    return Ok(Some(OutgoingKeyboardMessage::select_pack_language(
+                        user_id, &languages, locale,
+                    )));
+                }
+
+                Room::set_draft(user_id, "language", languages[0].code(), redis).await?;
+                Context::set_context(user_id, Context::SELECT_ORDER, redis).await?;
+
+                Ok(Some(OutgoingKeyboardMessage::select_order(user_id, locale)))
             } else {
                 Ok(Some(OutgoingKeyboardMessage::with_text(
                     user_id,
-                    Messages::ERROR_PACK_DOES_NOT_EXIST,
+                    locale.text(Messages::ERROR_PACK_DOES_NOT_EXIST),
                 )))
             }
         } else {
-            Ok(Some(OutgoingKeyboardMessage::with_text(
+            Ok(Some(OutgoingKeyboardMessage::error(user_id, locale)))
+        }
+    }
+
+    pub(crate) async fn select_pack_language(
+        user_id: i32,
+        language_opt: Option<Locale>,
+        locale: Locale,
+        redis: &mut RedisConnection,
+    ) -> Result<Option<OutgoingKeyboardMessage>, redis::RedisError> {
+        let pack = Room::draft(user_id, "pack", redis)
+            .await?
+            .unwrap_or_default();
+        let languages = Pack::languages(&pack, redis).await?;
+        let language_opt = language_opt.filter(|x| languages.contains(x));
+
+        match language_opt {
+            Some(language) => {
+                Room::set_draft(user_id, "language", language.code(), redis).await?;
+                Context::set_context(user_id, Context::SELECT_ORDER, redis).await?;
+
+                Ok(Some(OutgoingKeyboardMessage::select_order(user_id, locale)))
+            }
+            None => Ok(Some(OutgoingKeyboardMessage::with_text(
                 user_id,
-                Messages::ERROR,
-            )))
+                locale.text(Messages::ERROR_PACK_LANGUAGE_DOES_NOT_EXIST),
+            ))),
+        }
+    }
+
+    pub(crate) async fn select_order(
+        user_id: i32,
+        order_opt: Option<&'static str>,
+        locale: Locale,
+        client: &Client,
+        redis: &mut RedisConnection,
+        url: &str,
+        ch_url: &String,
+    ) -> BotResult<Option<OutgoingKeyboardMessage>> {
+        match order_opt {
+            Some(order) => {
+                Room::set_draft(user_id, "order", order, redis).await?;
+                let mode = Room::draft(user_id, "mode", redis).await?;
+
+                if mode.as_deref() == Some(RoomMode::SOLO)
+                    || mode.as_deref() == Some(RoomMode::ASYNC)
+                    || !settings().features.timers
+                {
+                    Handlers::create_from_draft(user_id, locale, client, redis, url, ch_url).await
+                } else {
+                    Context::set_context(user_id, Context::SELECT_TIMER, redis).await?;
+                    Ok(Some(OutgoingKeyboardMessage::select_timer(user_id, locale)))
+                }
+            }
+            None => Ok(Some(OutgoingKeyboardMessage::with_text(
+                user_id,
+                locale.text(Messages::ERROR_ORDER_DOES_NOT_EXIST),
+            ))),
+        }
+    }
+
+    pub(crate) async fn new_room(
+        user_id: i32,
+        timer_opt: Option<u64>,
+        locale: Locale,
+        client: &Client,
+        redis: &mut RedisConnection,
+        url: &str,
+        ch_url: &String,
+    ) -> BotResult<Option<OutgoingKeyboardMessage>> {
+        match timer_opt {
+            Some(timer) => {
+                Room::set_draft(user_id, "timer", &timer.to_string(), redis).await?;
+                Handlers::create_from_draft(user_id, locale, client, redis, url, ch_url).await
+            }
+            None => Ok(Some(OutgoingKeyboardMessage::with_text(
+                user_id,
+                locale.text(Messages::ERROR_TIMER_DOES_NOT_EXIST),
+            ))),
+        }
+    }
+
+    async fn create_from_draft(
+        user_id: i32,
+        locale: Locale,
+        client: &Client,
+        redis: &mut RedisConnection,
+        url: &str,
+        ch_url: &String,
+    ) -> BotResult<Option<OutgoingKeyboardMessage>> {
+        let draft = RoomDraft::get(user_id, redis).await?;
+
+        match draft {
+            Some(draft) => {
+                let room_id = Room::create(user_id, &draft, redis).await?;
+
+                if draft.mode == RoomMode::SOLO {
+                    Room::start(&room_id, redis, client, url, ch_url).await?;
+                    return Ok(None);
+                }
+
+                if draft.mode == RoomMode::ASYNC {
+                    let msg =
+                        OutgoingKeyboardMessage::async_start_message(user_id, &room_id, locale);
+                    send_message(url, &msg, client).await?;
+                    RoomEvent::record(RoomEvent::STARTED);
+
+                    return Room::enter_return(
+                        user_id,
+                        &room_id,
+                        Role::CREATOR,
+                        redis,
+                        client,
+                        url,
+                    )
+                    .await;
+                }
+
+                let msg = OutgoingKeyboardMessage::room_id_message(user_id, &room_id, locale);
+                Context::set_context(user_id, Context::WAITING_FOR_PARTNER, redis).await?;
+
+                Ok(Some(msg))
+            }
+            None => Ok(Some(OutgoingKeyboardMessage::error(user_id, locale))),
         }
     }
 
     pub(crate) async fn insert_id(
         user_id: i32,
         message: &Option<TgMessage>,
+        locale: Locale,
         client: &Client,
-        redis: &mut redis::Connection,
+        redis: &mut RedisConnection,
         url: &str,
         ch_url: &String,
-    ) -> Result<Option<OutgoingKeyboardMessage>, Box<dyn std::error::Error>> {
+    ) -> BotResult<Option<OutgoingKeyboardMessage>> {
         let id_opt = message.as_ref().and_then(|x| x.text.as_ref());
 
-        if id_opt.is_some() {
-            let room_id = id_opt.unwrap();
-            let room_key = Room::key(room_id, redis)?;
+        if let Some(room_id) = id_opt {
+            let room_key = Room::key(room_id, redis).await?;
             let room_users: Option<Vec<Option<i32>>> =
-                redis.hget(room_key, &["creator_id", "visitor_id"])?;
+                redis.hget(room_key, &["creator_id", "visitor_id"]).await?;
 
-            if room_users.is_some() {
-                let user_ids: [Option<i32>; 2] =
-                    room_users.unwrap().try_into().unwrap_or([None, None]);
+            if let Some(room_users) = room_users {
+                let user_ids: [Option<i32>; 2] = room_users.try_into().unwrap_or([None, None]);
 
                 match user_ids {
                     [Some(creator_id), Some(_)] if creator_id == user_id => {
-                        Room::enter_return(
-                            user_id,
-                            &room_id,
-                            Role::CREATOR,
-                            redis,
-                            client,
-                            &url.to_string(),
-                        )
-                        .await
+                        Room::enter_return(user_id, room_id, Role::CREATOR, redis, client, url)
+                            .await
                     }
                     [Some(_), Some(visitor_id)] if visitor_id == user_id => {
-                        Room::enter_return(
-                            user_id,
-                            &room_id,
-                            Role::VISITOR,
-                            redis,
-                            client,
-                            &url.to_string(),
-                        )
-                        .await
+                        Room::enter_return(user_id, room_id, Role::VISITOR, redis, client, url)
+                            .await
                     }
                     [_, None] => {
-                        Room::enter(room_id, user_id, redis)?;
+                        // someone else joined between the check and the entry
+                        if !Room::enter(room_id, user_id, redis).await? {
+                            return Ok(Some(OutgoingKeyboardMessage::room_taken(user_id, locale)));
+                        }
+
+                        // the creator of an async room is already going through the pack
+                        if RoomMode::get(room_id, redis).await? == RoomMode::ASYNC {
+                            return Room::enter_return(
+                                user_id,
+                                room_id,
+                                Role::VISITOR,
+                                redis,
+                                client,
+                                url,
+                            )
+                            .await;
+                        }
+
                         Room::start(room_id, redis, client, url, ch_url).await?;
                         Ok(None)
                     }
-                    _ => Ok(None),
+                    _ => Ok(Some(OutgoingKeyboardMessage::room_taken(user_id, locale))),
                 }
             } else {
-                Ok(Some(OutgoingKeyboardMessage::wrong_room_id(user_id)))
+                Ok(Some(OutgoingKeyboardMessage::wrong_room_id(
+                    user_id, locale,
+                )))
             }
         } else {
             Ok(Some(OutgoingKeyboardMessage::no_room_id_in_message(
-                user_id,
+                user_id, locale,
             )))
         }
     }
@@ -123,36 +269,226 @@ impl Handlers {
     pub(crate) async fn waiting_for_answer(
         user_id: i32,
         client: &Client,
-        redis: &mut redis::Connection,
-        url: &String,
+        redis: &mut RedisConnection,
+        url: &str,
         ch_url: &String,
-    ) -> Result<Option<OutgoingKeyboardMessage>, Box<dyn std::error::Error>> {
-        let user_room = UserRoom::get(user_id, redis)?;
-        if user_room.set_ready_time(redis)? {
-            Room::write_data(&user_room.id, redis, client, ch_url).await?;
-
-            let idx = Room::prepare_for_next_question(&user_room.id, redis)?;
-            let room_key = Room::key(&user_room.id, redis)?;
-            let users: Vec<i32> = redis.hget(&room_key, &["creator_id", "visitor_id"])?;
-            let pack: String = redis.hget(&room_key, "pack")?;
-            send_question_messages(
-                [users[0], users[1]],
-                &pack,
-                idx,
-                redis,
-                client,
-                url,
-                &user_room.id,
-                ch_url,
-            )
-            .await?;
+    ) -> BotResult<Option<OutgoingKeyboardMessage>> {
+        let user_room = UserRoom::get(user_id, redis).await?;
+
+        if RoomMode::get(&user_room.id, redis).await? == RoomMode::ASYNC {
+            return AsyncRoom::advance(user_id, &user_room, redis, client, url, ch_url).await;
+        }
+
+        if user_room.set_ready_time(redis).await? {
+            let idx = match Room::write_data(&user_room.id, redis, client, ch_url).await {
+                Ok(()) => Room::prepare_for_next_question(&user_room.id, redis)
+                    .await
+                    .map_err(Into::into),
+                Err(e) => Err(e),
+            };
+            let idx = match idx {
+                Ok(idx) => idx,
+                Err(e) => {
+                    user_room.undo_done("ready_at", redis).await?;
+                    return Err(e);
+                }
+            };
+
+            let users = Room::user_ids(&user_room.id, redis).await?;
+            send_question_messages(&users, idx, redis, client, url, &user_room.id, ch_url).await?;
 
             Ok(None)
         } else {
+            QuestionMessage::show_progress(&user_room.id, &user_room.role, redis, client, url)
+                .await?;
+
             Ok(Some(OutgoingKeyboardMessage::with_text(
                 user_id,
-                Messages::WAITING_FOR_PARTNER_EVAL,
+                Locale::get(user_id, redis)
+                    .await?
+                    .text(Messages::WAITING_FOR_PARTNER_EVAL),
             )))
         }
     }
+
+    /// The user has blocked the bot, so the room can't go on.
+    /// The partner is offered to go through the pack alone or to leave the room.
+    pub(crate) async fn unreachable(
+        user_id: i32,
+        client: &Client,
+        redis: &mut RedisConnection,
+        url: &str,
+    ) -> BotResult<()> {
+        tracing::warn!(blocked_user_id = user_id, "User has blocked the bot");
+        Unreachable::mark(user_id, redis).await?;
+
+        let room_id = match UserRoom::room_id(user_id, redis).await? {
+            Some(room_id) => room_id,
+            None => return Ok(()),
+        };
+
+        let users = Room::user_ids(&room_id, redis).await?;
+        let partners: Vec<i32> = users.into_iter().filter(|&x| x != user_id).collect();
+
+        for partner_id in Unreachable::reachable(&partners, redis).await? {
+            // the offer is made once, however many messages have failed
+            let context = Context::get(partner_id, redis).await?;
+            if context == Context::PARTNER_UNREACHABLE {
+                continue;
+            }
+            // a partner who has answered everything can only leave
+            let finished = context == Context::WAITING_FOR_RESULTS;
+
+            let locale = Locale::get(partner_id, redis).await?;
+            Context::set_context(partner_id, Context::PARTNER_UNREACHABLE, redis).await?;
+            QuestionKeyboards::strip(partner_id, redis, client, url).await?;
+            send_message(
+                url,
+                &OutgoingKeyboardMessage::partner_unreachable(partner_id, finished, locale),
+                client,
+            )
+            .await?;
+        }
+
+        Ok(())
+    }
+
+    /// Closes the room of the unreachable partner and starts a solo run of its pack.
+    pub(crate) async fn continue_alone(
+        user_id: i32,
+        client: &Client,
+        redis: &mut RedisConnection,
+        url: &str,
+        ch_url: &String,
+    ) -> BotResult<Option<OutgoingKeyboardMessage>> {
+        let user_room = UserRoom::get(user_id, redis).await?;
+        let is_async = RoomMode::get(&user_room.id, redis).await? == RoomMode::ASYNC;
+
+        // a user who has answered everything has no run left to go on with
+        if is_async && AsyncRoom::answered_all(&user_room, redis).await?.is_some() {
+            let locale = Locale::get(user_id, redis).await?;
+            return Ok(Some(OutgoingKeyboardMessage::partner_unreachable(
+                user_id, true, locale,
+            )));
+        }
+
+        let room_key = Room::key(&user_room.id, redis).await?;
+        let room: HashMap<String, String> = redis.hgetall(&room_key).await?;
+        let draft = RoomDraft {
+            pack: room.get("pack").cloned().unwrap_or_default(),
+            mode: RoomMode::SOLO.to_string(),
+            order: room
+                .get("order")
+                .cloned()
+                .unwrap_or_else(|| QuestionOrder::SEQUENTIAL.to_string()),
+            timer: 0,
+            language: room.get("language").cloned(),
+        };
+
+        let room_id = Room::create(user_id, &draft, redis).await?;
+        QuestionOrder::carry_over(&user_room.id, &room_id, &user_room.role, redis).await?;
+        // the answers of an async room are only kept until the room is cleared
+        if is_async {
+            AsyncRoom::write_data(&user_room.id, redis, client, ch_url).await?;
+        }
+
+        let users = Room::user_ids(&user_room.id, redis).await?;
+        Room::clear(&users, &user_room.id, redis).await?;
+        RoomEvent::record(RoomEvent::ABANDONED);
+
+        Room::start(&room_id, redis, client, url, ch_url).await?;
+
+        Ok(None)
+    }
+
+    pub(crate) async fn leave_room(
+        user_id: i32,
+        locale: Locale,
+        redis: &mut RedisConnection,
+    ) -> BotResult<Option<OutgoingKeyboardMessage>> {
+        let room_id = UserRoom::get(user_id, redis).await?.id;
+        let users = Room::user_ids(&room_id, redis).await?;
+        Room::clear(&users, &room_id, redis).await?;
+        RoomEvent::record(RoomEvent::ABANDONED);
+
+        Ok(Some(OutgoingKeyboardMessage::with_keyboard(
+            user_id,
+            locale.text(Messages::ROOM_LEFT),
+            InlineKeyboardMarkup::welcome(locale),
+        )))
+    }
+
+    pub(crate) async fn note(
+        user_id: i32,
+        message: &Option<TgMessage>,
+        locale: Locale,
+        redis: &mut RedisConnection,
+    ) -> BotResult<Option<OutgoingKeyboardMessage>> {
+        let user_room = UserRoom::get(user_id, redis).await?;
+        let note = message.as_ref().and_then(|x| x.text.as_ref());
+
+        match note {
+            Some(note) if RoomMode::get(&user_room.id, redis).await? == RoomMode::SOLO => {
+                SoloRun::add_note(&user_room.id, note, redis).await?;
+                Ok(Some(OutgoingKeyboardMessage::with_text(
+                    user_id,
+                    locale.text(Messages::NOTE_SAVED),
+                )))
+            }
+            _ => Err(BotError::Validation(Messages::ERROR)),
+        }
+    }
+
+    pub(crate) async fn compare(
+        user_id: i32,
+        message: &Option<TgMessage>,
+        locale: Locale,
+        client: &Client,
+        redis: &mut RedisConnection,
+        ch_url: &String,
+    ) -> BotResult<Option<OutgoingKeyboardMessage>> {
+        let run_id = message
+            .as_ref()
+            .and_then(|x| x.text.as_ref())
+            .and_then(|x| x.split_whitespace().nth(1));
+
+        let text = match run_id {
+            Some(run_id) => {
+                SoloRun::compare(user_id, run_id, locale, redis, client, ch_url).await?
+            }
+            None => locale.text(Messages::COMPARE_USAGE).to_string(),
+        };
+
+        Ok(Some(OutgoingKeyboardMessage {
+            chat_id: user_id,
+            text,
+            reply_markup: None,
+            parse_mode: Some("HTML".to_string()),
+        }))
+    }
+
+    pub(crate) async fn language(
+        user_id: i32,
+        message: &Option<TgMessage>,
+        locale: Locale,
+        redis: &mut RedisConnection,
+    ) -> Result<Option<OutgoingKeyboardMessage>, redis::RedisError> {
+        let code = message
+            .as_ref()
+            .and_then(|x| x.text.as_ref())
+            .and_then(|x| x.split_whitespace().nth(1));
+
+        let text = match code.map(Locale::from_code) {
+            Some(Some(selected)) => {
+                Locale::select(user_id, selected, redis).await?;
+                selected.text(Messages::LANGUAGE_CHANGED).to_string()
+            }
+            Some(None) => locale
+                .text(Messages::ERROR_LANGUAGE_DOES_NOT_EXIST)
+                .to_string(),
+            None => locale.format(Messages::LANGUAGE_USAGE, &[("languages", Locale::list())]),
+        };
+
+        Ok(Some(OutgoingKeyboardMessage::with_text(user_id, &text)))
+    }
 }
@@ -0,0 +1,184 @@
+use crate::settings::settings;
+use crate::storage::RedisConnection;
+use crate::ternary;
+
+use lazy_static::lazy_static;
+use redis::AsyncCommands;
+use std::collections::HashMap;
+
+type Catalog = HashMap<String, String>;
+
+lazy_static! {
+    static ref CATALOGS: HashMap<&'static str, Catalog> = [
+        (Locale::RU.0, include_str!("../../locales/ru.toml")),
+        (Locale::EN.0, include_str!("../../locales/en.toml")),
+    ]
+    .iter()
+    .map(|&(code, source)| (code, Locale::parse(code, source)))
+    .collect();
+}
+
+/// Language of the bot texts. Catalogs live in `locales/{code}.toml` and are compiled into the binary.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Locale(&'static str);
+impl Locale {
+    pub const RU: Locale = Locale("ru");
+    pub const EN: Locale = Locale("en");
+    pub const ALL: [Locale; 2] = [Locale::RU, Locale::EN];
+    pub const DEFAULT: Locale = Locale::RU;
+
+    fn parse(code: &str, source: &str) -> Catalog {
+        let value: toml::Value = toml::from_str(source)
+            .unwrap_or_else(|e| panic!("Invalid {} locale catalog: {}", code, e));
+        let mut catalog = Catalog::new();
+        Locale::flatten("", &value, &mut catalog);
+        catalog
+    }
+
+    // nested tables become dotted keys: [messages] welcome = ... -> messages.welcome
+    fn flatten(prefix: &str, value: &toml::Value, catalog: &mut Catalog) {
+        match value {
+            toml::Value::Table(table) => {
+                for (key, value) in table.iter() {
+                    let key = ternary!(
+                        prefix.is_empty(),
+                        key.to_string(),
+                        format!("{}.{}", prefix, key)
+                    );
+                    Locale::flatten(&key, value, catalog);
+                }
+            }
+            toml::Value::String(text) => {
+                catalog.insert(prefix.to_string(), text.to_string());
+            }
+            _ => {}
+        }
+    }
+
+    pub fn code(&self) -> &'static str {
+        self.0
+    }
+
+    pub fn name(&self) -> &'static str {
+        self.text("name")
+    }
+
+    pub fn from_name(name: &str) -> Option<Locale> {
+        Locale::ALL
+            .iter()
+            .find(|locale| locale.name() == name)
+            .copied()
+    }
+
+    /// Finds a supported locale for a Telegram `language_code` like `en` or `en-US`.
+    pub fn from_code(code: &str) -> Option<Locale> {
+        let language = code.split(&['-', '_'][..]).next().unwrap_or("");
+
+        Locale::ALL
+            .iter()
+            .find(|locale| locale.0.eq_ignore_ascii_case(language))
+            .copied()
+    }
+
+    /// Text of the key, falls back to the default locale and then to the key itself.
+    pub fn text(&self, key: &'static str) -> &'static str {
+        [*self, Locale::DEFAULT]
+            .iter()
+            .find_map(|locale| CATALOGS.get(locale.0).and_then(|x| x.get(key)))
+            .map(|x| x.as_str())
+            .unwrap_or_else(|| {
+                tracing::warn!(key, locale = self.0, "No text in the locale");
+                key
+            })
+    }
+
+    /// Text of the key with `{name}` placeholders replaced by the values.
+    /// Placeholders are replaced in one pass, so values may contain braces themselves.
+    pub fn format(&self, key: &'static str, values: &[(&str, String)]) -> String {
+        let mut text = self.text(key);
+        let mut result = String::with_capacity(text.len());
+
+        while let Some(start) = text.find('{') {
+            result.push_str(&text[..start]);
+            text = &text[start..];
+
+            let value = text.find('}').and_then(|end| {
+                values
+                    .iter()
+                    .find(|(name, _)| *name == &text[1..end])
+                    .map(|(_, value)| (end, value))
+            });
+
+            match value {
+                Some((end, value)) => {
+                    result.push_str(value);
+                    text = &text[end + 1..];
+                }
+                None => {
+                    result.push('{');
+                    text = &text[1..];
+                }
+            }
+        }
+
+        result.push_str(text);
+        result
+    }
+
+    fn key_selected_str(user_id: i32) -> String {
+        format!("user:{}:locale", user_id)
+    }
+
+    fn key_language_code_str(user_id: i32) -> String {
+        format!("user:{}:language_code", user_id)
+    }
+
+    /// Locale chosen with /language, otherwise the one of the user's Telegram client.
+    pub async fn get(user_id: i32, redis: &mut RedisConnection) -> redis::RedisResult<Locale> {
+        let codes: Vec<Option<String>> = redis
+            .get(&[
+                Locale::key_selected_str(user_id),
+                Locale::key_language_code_str(user_id),
+            ])
+            .await?;
+
+        Ok(codes
+            .iter()
+            .flatten()
+            .find_map(|code| Locale::from_code(code))
+            .unwrap_or(Locale::DEFAULT))
+    }
+
+    /// Remembers the language of the user's Telegram client,
+    /// so that messages caused by the partner are sent in the right language too.
+    pub(crate) async fn remember(
+        user_id: i32,
+        language_code: &str,
+        redis: &mut RedisConnection,
+    ) -> redis::RedisResult<()> {
+        redis
+            .set_ex(
+                Locale::key_language_code_str(user_id),
+                language_code,
+                settings().storage.ttl,
+            )
+            .await
+    }
+
+    pub(crate) async fn select(
+        user_id: i32,
+        locale: Locale,
+        redis: &mut RedisConnection,
+    ) -> redis::RedisResult<()> {
+        redis.set(Locale::key_selected_str(user_id), locale.0).await
+    }
+
+    /// Supported locales with their names, one per line.
+    pub fn list() -> String {
+        Locale::ALL
+            .iter()
+            .map(|locale| format!("{} – {}", locale.0, locale.name()))
+            .collect::<Vec<String>>()
+            .join("\n")
+    }
+}
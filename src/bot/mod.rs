@@ -1,4 +1,11 @@
+pub mod async_room;
 pub mod constants;
 pub mod handlers;
+pub mod locale;
+pub mod order;
+pub mod pack;
 pub mod report;
 pub mod room;
+pub mod scripts;
+pub mod solo;
+pub mod timer;
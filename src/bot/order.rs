@@ -0,0 +1,304 @@
+use crate::bot::constants::Keys;
+use crate::bot::locale::Locale;
+use crate::bot::pack::Pack;
+use crate::bot::room::{Room, RoomMode};
+use crate::settings::settings;
+use crate::storage::RedisConnection;
+use crate::ternary;
+use crate::tools::get_parse_string_value;
+
+use rand::rngs::StdRng;
+use rand::seq::SliceRandom;
+use rand::SeedableRng;
+use redis::AsyncCommands;
+use std::collections::HashMap;
+
+// categories nobody has rated yet compete as if both partners rated them in the middle of the scale
+const ADAPTIVE_PRIOR: f32 = 2.0;
+
+pub struct QuestionOrder;
+impl QuestionOrder {
+    pub const SEQUENTIAL: &'static str = "sequential";
+    pub const SHUFFLED: &'static str = "shuffled";
+    pub const QUICK: &'static str = "quick";
+    pub const ADAPTIVE: &'static str = "adaptive";
+    pub const ALL: [&'static str; 4] = [
+        QuestionOrder::SEQUENTIAL,
+        QuestionOrder::SHUFFLED,
+        QuestionOrder::QUICK,
+        QuestionOrder::ADAPTIVE,
+    ];
+
+    pub const KEYS: [&'static str; 4] = [
+        Keys::ORDER_SEQUENTIAL,
+        Keys::ORDER_SHUFFLED,
+        Keys::ORDER_QUICK,
+        Keys::ORDER_ADAPTIVE,
+    ];
+
+    /// Text of an order button or prompt, the quick order tells how many questions it asks.
+    pub(crate) fn text(key: &'static str, locale: Locale) -> String {
+        locale.format(key, &[("count", settings().rooms.quick_length.to_string())])
+    }
+
+    pub fn from_key(text: &str) -> Option<&'static str> {
+        QuestionOrder::KEYS
+            .iter()
+            .position(|&key| {
+                Locale::ALL
+                    .iter()
+                    .any(|&locale| QuestionOrder::text(key, locale) == text)
+            })
+            .map(|i| QuestionOrder::ALL[i])
+    }
+
+    fn key_asked_str(room_id: &str) -> String {
+        format!("room:{}:asked", room_id)
+    }
+
+    fn key_scores_str(room_id: &str) -> String {
+        format!("room:{}:scores", room_id)
+    }
+
+    fn key_categories_str(pack: &str) -> String {
+        format!("pack:{}:categories", pack)
+    }
+
+    pub(crate) fn keys(room_id: &str) -> [String; 2] {
+        [
+            QuestionOrder::key_asked_str(room_id),
+            QuestionOrder::key_scores_str(room_id),
+        ]
+    }
+
+    /// Room fields describing the order, stored next to the rest of the room on creation.
+    pub(crate) async fn room_fields(
+        order: &str,
+        pack: &str,
+        redis: &mut RedisConnection,
+    ) -> redis::RedisResult<Vec<(&'static str, String)>> {
+        let pack_len = Pack::len(pack, redis).await?;
+        let mut fields = vec![("order", order.to_string())];
+
+        if order == QuestionOrder::SHUFFLED || order == QuestionOrder::QUICK {
+            fields.push(("seed", rand::random::<u64>().to_string()));
+        }
+
+        let length = ternary!(
+            order == QuestionOrder::QUICK,
+            pack_len.min(settings().rooms.quick_length),
+            pack_len
+        );
+        fields.push(("length", length.to_string()));
+
+        Ok(fields)
+    }
+
+    /// Moves the order and the current question of the role in a room to another one,
+    /// so that a run which replaces the room goes on where the role has stopped.
+    /// Must be called before the room is cleared.
+    pub(crate) async fn carry_over(
+        from: &String,
+        to: &String,
+        role: &str,
+        redis: &mut RedisConnection,
+    ) -> redis::RedisResult<()> {
+        let from_key = Room::key(from, redis).await?;
+        let room: HashMap<String, String> = redis.hgetall(&from_key).await?;
+        // partners of an async room each have their own question
+        let idx_field = ternary!(
+            room.get("mode").map(|x| x.as_str()) == Some(RoomMode::ASYNC),
+            format!("{}_idx", role),
+            "idx".to_string()
+        );
+        let mut fields: Vec<(&str, &String)> = ["order", "seed", "length"]
+            .iter()
+            .filter_map(|&field| room.get(field).map(|value| (field, value)))
+            .collect();
+        if let Some(idx) = room.get(&idx_field) {
+            fields.push(("idx", idx));
+        }
+
+        let room_key = Room::key(to, redis).await?;
+        let _: () = redis.hset_multiple(&room_key, &fields).await?;
+
+        for (from_key, to_key) in QuestionOrder::keys(from)
+            .iter()
+            .zip(QuestionOrder::keys(to).iter())
+        {
+            if redis.exists(from_key).await? {
+                let _: () = redis.rename(from_key, to_key).await?;
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Number of questions the room will go through.
+    pub(crate) fn length(room: &HashMap<String, String>, pack_len: u16) -> u16 {
+        get_parse_string_value(room, "length", pack_len).min(pack_len)
+    }
+
+    /// Maps the room question number to the position of the question in the pack list.
+    pub(crate) async fn position(
+        room_id: &String,
+        idx: u16,
+        redis: &mut RedisConnection,
+    ) -> redis::RedisResult<Option<u16>> {
+        let room_key = Room::key(room_id, redis).await?;
+        let room: HashMap<String, String> = redis.hgetall(&room_key).await?;
+        let pack = room.get("pack").cloned().unwrap_or_default();
+        let pack_len = Pack::len(&pack, redis).await?;
+
+        if idx >= QuestionOrder::length(&room, pack_len) {
+            return Ok(None);
+        }
+
+        let order = room
+            .get("order")
+            .map(|x| x.as_str())
+            .unwrap_or(QuestionOrder::SEQUENTIAL);
+
+        match order {
+            QuestionOrder::SHUFFLED | QuestionOrder::QUICK => {
+                let seed: u64 = get_parse_string_value(&room, "seed", 0);
+                Ok(QuestionOrder::shuffled(seed, pack_len)
+                    .get(idx as usize)
+                    .copied())
+            }
+            QuestionOrder::ADAPTIVE => {
+                QuestionOrder::adaptive_position(room_id, &pack, idx, pack_len, redis).await
+            }
+            _ => Ok(Some(idx)),
+        }
+    }
+
+    fn shuffled(seed: u64, pack_len: u16) -> Vec<u16> {
+        let mut positions: Vec<u16> = (0..pack_len).collect();
+        positions.shuffle(&mut StdRng::seed_from_u64(seed));
+        positions
+    }
+
+    async fn adaptive_position(
+        room_id: &str,
+        pack: &str,
+        idx: u16,
+        pack_len: u16,
+        redis: &mut RedisConnection,
+    ) -> redis::RedisResult<Option<u16>> {
+        let asked_key = QuestionOrder::key_asked_str(room_id);
+        let asked: Vec<u16> = redis.lrange(&asked_key, 0, -1).await?;
+
+        if let Some(&position) = asked.get(idx as usize) {
+            return Ok(Some(position));
+        }
+
+        let categories: Vec<String> = redis
+            .lrange(QuestionOrder::key_categories_str(pack), 0, -1)
+            .await?;
+        let scores: HashMap<String, String> = redis
+            .hgetall(QuestionOrder::key_scores_str(room_id))
+            .await?;
+
+        let category_score = |position: u16| -> f32 {
+            let category = match categories.get(position as usize) {
+                Some(category) => category,
+                None => return ADAPTIVE_PRIOR,
+            };
+            let count: f32 = get_parse_string_value(&scores, &format!("{}:count", category), 0.0);
+            let sum: f32 = get_parse_string_value(&scores, &format!("{}:sum", category), 0.0);
+
+            ternary!(count > 0.0, sum / count, ADAPTIVE_PRIOR)
+        };
+
+        // the highest rated category wins, earlier questions of the pack win the ties
+        let next = (0..pack_len)
+            .filter(|position| !asked.contains(position))
+            .fold(None, |best: Option<(u16, f32)>, position| {
+                let score = category_score(position);
+                match best {
+                    Some((_, best_score)) if best_score >= score => best,
+                    _ => Some((position, score)),
+                }
+            })
+            .map(|(position, _)| position);
+
+        if let Some(position) = next {
+            let _: () = redis.rpush(&asked_key, position).await?;
+            let _: () = redis.expire(&asked_key, settings().storage.ttl).await?;
+        }
+
+        Ok(next)
+    }
+
+    /// Remembers how important the current question's category was to both partners,
+    /// or to the user of a solo room.
+    /// Must be called before the ratings of the current question are removed from the room.
+    pub(crate) async fn record_scores(
+        room_id: &String,
+        redis: &mut RedisConnection,
+    ) -> redis::RedisResult<()> {
+        let room_key = Room::key(room_id, redis).await?;
+        let room: HashMap<String, String> = redis.hgetall(&room_key).await?;
+        let idx: u16 = get_parse_string_value(&room, "idx", 0);
+        let creator_importance: i64 = get_parse_string_value(&room, "creator_importance", 0);
+        // solo rooms have no visitor, the creator's importance is the score then
+        let score = match room.get("visitor_id") {
+            Some(_) => {
+                creator_importance.min(get_parse_string_value(&room, "visitor_importance", 0))
+            }
+            None => creator_importance,
+        };
+
+        QuestionOrder::add_score(room_id, &room, idx, score, redis).await
+    }
+
+    /// In async rooms partners answer a question at different times,
+    /// so the importance each of them gives is recorded on its own.
+    pub(crate) async fn record_answer(
+        room_id: &String,
+        idx: u16,
+        importance: i64,
+        redis: &mut RedisConnection,
+    ) -> redis::RedisResult<()> {
+        let room_key = Room::key(room_id, redis).await?;
+        let room: HashMap<String, String> = redis.hgetall(&room_key).await?;
+
+        QuestionOrder::add_score(room_id, &room, idx, importance, redis).await
+    }
+
+    async fn add_score(
+        room_id: &String,
+        room: &HashMap<String, String>,
+        idx: u16,
+        score: i64,
+        redis: &mut RedisConnection,
+    ) -> redis::RedisResult<()> {
+        if room.get("order").map(|x| x.as_str()) != Some(QuestionOrder::ADAPTIVE) {
+            return Ok(());
+        }
+
+        let position = match QuestionOrder::position(room_id, idx, redis).await? {
+            Some(position) => position,
+            None => return Ok(()),
+        };
+        let pack = room.get("pack").cloned().unwrap_or_default();
+        let category: Option<String> = redis
+            .lindex(QuestionOrder::key_categories_str(&pack), position as isize)
+            .await?;
+
+        if let Some(category) = category {
+            let scores_key = QuestionOrder::key_scores_str(room_id);
+
+            let _: () = redis
+                .hincr(&scores_key, format!("{}:sum", category), score)
+                .await?;
+            let _: () = redis
+                .hincr(&scores_key, format!("{}:count", category), 1)
+                .await?;
+            let _: () = redis.expire(&scores_key, settings().storage.ttl).await?;
+        }
+
+        Ok(())
+    }
+}
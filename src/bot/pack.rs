@@ -0,0 +1,111 @@
+use crate::bot::constants::RedisKeys;
+use crate::bot::locale::Locale;
+use crate::storage::RedisConnection;
+use crate::ternary;
+
+use redis::AsyncCommands;
+
+/// A question pack. `pack:{name}` holds the questions in the pack language,
+/// `pack:{name}:translation:{code}` hold translations with the same positions,
+/// so a question is identified by its position whatever language it is shown in.
+pub struct Pack;
+impl Pack {
+    pub(crate) fn key_str(pack: &str) -> String {
+        format!("pack:{}", pack)
+    }
+
+    fn key_translation_str(pack: &str, locale: Locale) -> String {
+        format!("pack:{}:translation:{}", pack, locale.code())
+    }
+
+    fn key_language_str(pack: &str) -> String {
+        format!("pack:{}:language", pack)
+    }
+
+    fn key_languages_str(pack: &str) -> String {
+        format!("pack:{}:languages", pack)
+    }
+
+    /// Names of all packs in the order of the pack buttons.
+    pub(crate) async fn names(redis: &mut RedisConnection) -> redis::RedisResult<Vec<String>> {
+        let mut packs: Vec<String> = redis.smembers(RedisKeys::PACKS).await?;
+        packs.sort();
+        Ok(packs)
+    }
+
+    /// Pack a button stands for, buttons carry a prefix of the name when it is too long.
+    pub(crate) async fn by_button(
+        prefix: &str,
+        redis: &mut RedisConnection,
+    ) -> redis::RedisResult<Option<String>> {
+        let names = Pack::names(redis).await?;
+        if names.iter().any(|name| name == prefix) {
+            return Ok(Some(prefix.to_string()));
+        }
+
+        let mut matching = names.into_iter().filter(|name| name.starts_with(prefix));
+        Ok(match (matching.next(), matching.next()) {
+            (Some(name), None) => Some(name),
+            _ => None,
+        })
+    }
+
+    pub(crate) async fn len(pack: &str, redis: &mut RedisConnection) -> redis::RedisResult<u16> {
+        redis.llen(Pack::key_str(pack)).await
+    }
+
+    /// Language of the `pack:{name}` list, packs without one are in the default locale.
+    pub(crate) async fn language(
+        pack: &str,
+        redis: &mut RedisConnection,
+    ) -> redis::RedisResult<Locale> {
+        let code: Option<String> = redis.get(Pack::key_language_str(pack)).await?;
+
+        Ok(code
+            .and_then(|x| Locale::from_code(&x))
+            .unwrap_or(Locale::DEFAULT))
+    }
+
+    /// The pack language followed by the languages listed in `pack:{name}:languages`.
+    pub(crate) async fn languages(
+        pack: &str,
+        redis: &mut RedisConnection,
+    ) -> redis::RedisResult<Vec<Locale>> {
+        let language = Pack::language(pack, redis).await?;
+        let codes: Vec<String> = redis.smembers(Pack::key_languages_str(pack)).await?;
+        let mut languages = vec![language];
+
+        for locale in Locale::ALL.iter() {
+            if codes.iter().any(|x| x == locale.code()) && !languages.contains(locale) {
+                languages.push(*locale);
+            }
+        }
+
+        Ok(languages)
+    }
+
+    /// Text of the question in the first of the locales the pack is translated to.
+    pub(crate) async fn question(
+        pack: &str,
+        position: u16,
+        locales: &[Locale],
+        redis: &mut RedisConnection,
+    ) -> redis::RedisResult<Option<String>> {
+        let language = Pack::language(pack, redis).await?;
+
+        for &locale in locales.iter() {
+            let key = ternary!(
+                locale == language,
+                Pack::key_str(pack),
+                Pack::key_translation_str(pack, locale)
+            );
+            let question: Option<String> = redis.lindex(key, position as isize).await?;
+
+            if question.is_some() {
+                return Ok(question);
+            }
+        }
+
+        redis.lindex(Pack::key_str(pack), position as isize).await
+    }
+}
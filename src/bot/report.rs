@@ -1,4 +1,8 @@
+use crate::analytics;
+use crate::bot::constants::Reports;
+use crate::bot::locale::Locale;
 use crate::bot::room::Role;
+use crate::error::BotResult;
 use crate::ternary;
 
 use reqwest::Client;
@@ -12,6 +16,7 @@ pub struct ReportData {
     share_positive_visitor: Option<f32>,
     creator_avg: Option<f32>,
     visitor_avg: Option<f32>,
+    avg_question_time: Option<f32>,
 }
 
 impl ReportData {
@@ -22,23 +27,14 @@ impl ReportData {
             && self.visitor_avg.is_none()
     }
 
-    pub async fn get(
-        room_id: &String,
-        client: &Client,
-        ch_url: &String,
-    ) -> Result<ReportData, Box<dyn std::error::Error>> {
-        let res = client
-            .post(ch_url)
-            .body(ReportData::generate_request(room_id))
-            .send()
-            .await?
-            .bytes()
-            .await?;
+    pub async fn get(room_id: &String, client: &Client, ch_url: &String) -> BotResult<ReportData> {
+        let query = ReportData::generate_request(room_id);
+        let res = analytics::query(client, ch_url, query, "report").await?;
 
         Ok(serde_json::from_slice::<ReportData>(&res)?)
     }
 
-    pub(crate) fn generate_report(&self, role: &String) -> String {
+    pub(crate) fn generate_report(&self, role: &String, locale: Locale) -> String {
         let your_total: i32 = ternary!(
             role == Role::CREATOR,
             self.creator_total,
@@ -70,20 +66,33 @@ impl ReportData {
             self.visitor_avg.unwrap_or(0.0)
         );
 
-        format!(
-            r#"✨<b>Твой отчет:</b>
-🤗Ты оценил партнера на <i>{your_total}</i>, а он тебя – на <i>{other_total}</i>.
-
-💥Позитивную оценку получили <i>{other_share_positive:.1}%</i> твоих ответов, а ты положительно оценил <i>{your_share_positive:.1}%</i> ответов партнера.
-
-🃏Средняя оценка твоих ответов: <i>{other_avg:.1}</i>, а ты оценивал ответы партнера в среднем на <i>{your_avg:.1}</i>."#,
-            your_total = your_total,
-            other_total = other_total,
-            other_share_positive = other_share_positive,
-            your_share_positive = your_share_positive,
-            other_avg = other_avg,
-            your_avg = your_avg
-        )
+        locale.format(
+            Reports::ROOM,
+            &[
+                ("your_total", your_total.to_string()),
+                ("other_total", other_total.to_string()),
+                (
+                    "other_share_positive",
+                    format!("{:.1}", other_share_positive),
+                ),
+                ("your_share_positive", format!("{:.1}", your_share_positive)),
+                ("other_avg", format!("{:.1}", other_avg)),
+                ("your_avg", format!("{:.1}", your_avg)),
+            ],
+        ) + &self.generate_time_report(locale)
+    }
+
+    fn generate_time_report(&self, locale: Locale) -> String {
+        match self.avg_question_time {
+            Some(time) if time > 0.0 => locale.format(
+                Reports::ROOM_TIME,
+                &[
+                    ("minutes", (time as u32 / 60).to_string()),
+                    ("seconds", (time as u32 % 60).to_string()),
+                ],
+            ),
+            _ => String::new(),
+        }
     }
 
     fn generate_request(room_id: &String) -> String {
@@ -96,15 +105,239 @@ impl ReportData {
                 toInt32(sum(creator_score)) as creator_total,
                 toInt32(sum(visitor_score)) as visitor_total,
                 avg(creator_score) as creator_avg,
-                avg(visitor_score) as visitor_avg
+                avg(visitor_score) as visitor_avg,
+                (
+                    select avgIf(greatest(creator_ready_at, visitor_ready_at) - question_started_at, question_started_at > 0)
+                    from tg_room_bot
+                    where room_id = '{room_id}'
+                ) as avg_question_time
             from (
                 select
                     creator_importance * (creator_evaluation - 2) as creator_score,
                     visitor_importance * (visitor_evaluation - 2) as visitor_score
                 from tg_room_bot
-                where room_id = '{}' and [creator_score, visitor_score] != [0, 0]
+                where room_id = '{room_id}' and [creator_score, visitor_score] != [0, 0]
             ) format JSONEachRow"#,
+            room_id = room_id
+        )
+    }
+}
+
+#[derive(Deserialize, Debug, PartialEq)]
+pub struct GuessReportData {
+    creator_guessed: Option<f32>,
+    visitor_guessed: Option<f32>,
+    creator_guess_error: Option<f32>,
+    visitor_guess_error: Option<f32>,
+}
+
+impl GuessReportData {
+    pub async fn get(
+        room_id: &String,
+        client: &Client,
+        ch_url: &String,
+    ) -> BotResult<GuessReportData> {
+        let query = GuessReportData::generate_request(room_id);
+        let res = analytics::query(client, ch_url, query, "guess_report").await?;
+
+        Ok(serde_json::from_slice::<GuessReportData>(&res)?)
+    }
+
+    pub(crate) fn generate_report(&self, role: &String, locale: Locale) -> String {
+        let your_guessed: f32 = ternary!(
+            role == Role::CREATOR,
+            self.creator_guessed.unwrap_or(0.0),
+            self.visitor_guessed.unwrap_or(0.0)
+        );
+        let other_guessed: f32 = ternary!(
+            role == Role::CREATOR,
+            self.visitor_guessed.unwrap_or(0.0),
+            self.creator_guessed.unwrap_or(0.0)
+        );
+        let your_error: f32 = ternary!(
+            role == Role::CREATOR,
+            self.creator_guess_error.unwrap_or(0.0),
+            self.visitor_guess_error.unwrap_or(0.0)
+        );
+        let other_error: f32 = ternary!(
+            role == Role::CREATOR,
+            self.visitor_guess_error.unwrap_or(0.0),
+            self.creator_guess_error.unwrap_or(0.0)
+        );
+
+        locale.format(
+            Reports::GUESS,
+            &[
+                ("your_guessed", format!("{:.1}", your_guessed)),
+                ("other_guessed", format!("{:.1}", other_guessed)),
+                ("your_error", format!("{:.1}", your_error)),
+                ("other_error", format!("{:.1}", other_error)),
+            ],
+        )
+    }
+
+    fn generate_request(room_id: &String) -> String {
+        format!(
+            r#"
+            select
+                countIf(creator_guess = visitor_importance) / count() * 100 as creator_guessed,
+                countIf(visitor_guess = creator_importance) / count() * 100 as visitor_guessed,
+                avg(abs(creator_guess - visitor_importance)) as creator_guess_error,
+                avg(abs(visitor_guess - creator_importance)) as visitor_guess_error
+            from tg_room_bot
+            where room_id = '{}' and creator_guess >= 0 and visitor_guess >= 0
+            format JSONEachRow"#,
             room_id
         )
     }
 }
+
+fn question_list(questions: &[String]) -> String {
+    questions
+        .iter()
+        .map(|x| format!("• {}", x))
+        .collect::<Vec<String>>()
+        .join("\n")
+}
+
+#[derive(Deserialize, Debug, PartialEq)]
+pub struct SoloReportData {
+    total_questions: u16,
+    avg_importance: Option<f32>,
+    share_important: Option<f32>,
+    top_positions: Vec<u16>,
+}
+
+impl SoloReportData {
+    pub async fn get(
+        run_id: &String,
+        client: &Client,
+        ch_url: &String,
+    ) -> BotResult<SoloReportData> {
+        let query = SoloReportData::generate_request(run_id);
+        let res = analytics::query(client, ch_url, query, "solo_report").await?;
+
+        Ok(serde_json::from_slice::<SoloReportData>(&res)?)
+    }
+
+    pub fn top_positions(&self) -> &[u16] {
+        &self.top_positions
+    }
+
+    pub(crate) fn generate_report(
+        &self,
+        run_id: &str,
+        top_questions: &[String],
+        locale: Locale,
+    ) -> String {
+        let top = ternary!(
+            top_questions.is_empty(),
+            String::new(),
+            locale.format(
+                Reports::SOLO_TOP,
+                &[("questions", question_list(top_questions))]
+            )
+        );
+
+        locale.format(
+            Reports::SOLO,
+            &[
+                ("total_questions", self.total_questions.to_string()),
+                (
+                    "avg_importance",
+                    format!("{:.1}", self.avg_importance.unwrap_or(0.0)),
+                ),
+                (
+                    "share_important",
+                    format!("{:.1}", self.share_important.unwrap_or(0.0)),
+                ),
+                ("top", top),
+                ("run_id", run_id.to_string()),
+            ],
+        )
+    }
+
+    fn generate_request(run_id: &String) -> String {
+        format!(
+            r#"
+            select
+                toUInt16(count()) as total_questions,
+                avg(importance) as avg_importance,
+                countIf(importance >= 2) / total_questions * 100 as share_important,
+                arrayMap(x -> toUInt16(x.2), arraySlice(arrayReverseSort(groupArrayIf((importance, position), importance >= 2)), 1, 3)) as top_positions
+            from tg_solo_runs
+            where run_id = '{}'
+            format JSONEachRow"#,
+            run_id
+        )
+    }
+}
+
+#[derive(Deserialize, Debug, PartialEq)]
+pub struct SoloComparisonData {
+    total_questions: u16,
+    share_same: Option<f32>,
+    avg_difference: Option<f32>,
+    diverging_positions: Vec<u16>,
+}
+
+impl SoloComparisonData {
+    pub async fn get(
+        run_id: &str,
+        other_run_id: &str,
+        client: &Client,
+        ch_url: &String,
+    ) -> BotResult<SoloComparisonData> {
+        let query = SoloComparisonData::generate_request(run_id, other_run_id);
+        let res = analytics::query(client, ch_url, query, "solo_comparison").await?;
+
+        Ok(serde_json::from_slice::<SoloComparisonData>(&res)?)
+    }
+
+    pub fn diverging_positions(&self) -> &[u16] {
+        &self.diverging_positions
+    }
+
+    pub(crate) fn generate_report(&self, diverging_questions: &[String], locale: Locale) -> String {
+        let diverging = ternary!(
+            diverging_questions.is_empty(),
+            String::new(),
+            locale.format(
+                Reports::COMPARISON_DIVERGING,
+                &[("questions", question_list(diverging_questions))]
+            )
+        );
+
+        locale.format(
+            Reports::COMPARISON,
+            &[
+                ("total_questions", self.total_questions.to_string()),
+                (
+                    "share_same",
+                    format!("{:.1}", self.share_same.unwrap_or(0.0)),
+                ),
+                (
+                    "avg_difference",
+                    format!("{:.1}", self.avg_difference.unwrap_or(0.0)),
+                ),
+                ("diverging", diverging),
+            ],
+        )
+    }
+
+    fn generate_request(run_id: &str, other_run_id: &str) -> String {
+        format!(
+            r#"
+            select
+                toUInt16(count()) as total_questions,
+                countIf(own.importance = other.importance) / total_questions * 100 as share_same,
+                avg(abs(own.importance - other.importance)) as avg_difference,
+                arrayMap(x -> toUInt16(x.2), arraySlice(arrayReverseSort(groupArrayIf((abs(own.importance - other.importance), position), own.importance != other.importance)), 1, 3)) as diverging_positions
+            from (select position, importance from tg_solo_runs where run_id = '{}') as own
+            inner join (select position, importance from tg_solo_runs where run_id = '{}') as other
+            using position
+            format JSONEachRow"#,
+            run_id, other_run_id
+        )
+    }
+}
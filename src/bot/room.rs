@@ -1,8 +1,20 @@
+use crate::analytics;
+use crate::bot::async_room::AsyncRoom;
+use crate::bot::constants::RedisKeys;
+use crate::bot::order::QuestionOrder;
+use crate::bot::scripts::{JOIN_ROOM, MARK_DONE};
+use crate::bot::solo::SoloRun;
+use crate::bot::timer::QuestionTimer;
+use crate::error::{BotError, BotResult};
+use crate::metrics::{RoomEvent, QUESTIONS_ANSWERED};
+use crate::settings::settings;
+use crate::storage::RedisConnection;
 use crate::telegram::messages::*;
+use crate::ternary;
 use crate::tools::*;
 
 use crate::telegram::structures::OutgoingKeyboardMessage;
-use redis::{Commands, Connection};
+use redis::AsyncCommands;
 use reqwest::Client;
 use serde::Deserialize;
 use std::borrow::Borrow;
@@ -11,7 +23,7 @@ use std::convert::TryInto;
 
 pub struct Role;
 impl Role {
-    fn opposite(role: &String) -> String {
+    pub(crate) fn opposite(role: &String) -> String {
         if role == Role::CREATOR {
             Role::VISITOR.to_string()
         } else {
@@ -19,9 +31,12 @@ impl Role {
         }
     }
 
-    pub fn get(user_id: i32, redis: &mut Connection) -> Result<String, redis::RedisError> {
-        let user_key = Room::key_user(user_id, redis)?;
-        let role: String = redis.hget(user_key, "role")?;
+    pub async fn get(
+        user_id: i32,
+        redis: &mut RedisConnection,
+    ) -> Result<String, redis::RedisError> {
+        let user_key = Room::key_user(user_id, redis).await?;
+        let role: String = redis.hget(user_key, "role").await?;
         Ok(role)
     }
 
@@ -29,17 +44,52 @@ impl Role {
     pub const VISITOR: &'static str = "visitor";
 }
 
+pub struct RoomMode;
+impl RoomMode {
+    pub async fn get(
+        room_id: &String,
+        redis: &mut RedisConnection,
+    ) -> Result<String, redis::RedisError> {
+        let room_key = Room::key(room_id, redis).await?;
+        let mode: Option<String> = redis.hget(room_key, "mode").await?;
+        Ok(mode.unwrap_or_else(|| RoomMode::CLASSIC.to_string()))
+    }
+
+    /// Room fields both roles have to set before they can move on to the next question.
+    pub fn rating_fields(mode: &str) -> Vec<&'static str> {
+        match mode {
+            RoomMode::GUESS => vec!["importance", "evaluation", "guess"],
+            RoomMode::SOLO => vec!["importance"],
+            _ => vec!["importance", "evaluation"],
+        }
+    }
+
+    pub const CLASSIC: &'static str = "classic";
+    pub const GUESS: &'static str = "guess";
+    pub const SOLO: &'static str = "solo";
+    pub const ASYNC: &'static str = "async";
+    pub const ALL: [&'static str; 4] = [
+        RoomMode::CLASSIC,
+        RoomMode::GUESS,
+        RoomMode::SOLO,
+        RoomMode::ASYNC,
+    ];
+}
+
+type RoomUsers = (Option<i32>, Option<i32>);
+
 #[derive(Debug, Deserialize, PartialEq)]
 pub struct Room {
     room_id: String,
     creator_id: i32,
     visitor_id: Option<i32>,
     pack: String,
+    mode: String,
     created_at: u64,
 }
 
 impl Room {
-    fn key_str(room_id: &String) -> String {
+    pub(crate) fn key_str(room_id: &String) -> String {
         format!("room:{}", room_id)
     }
 
@@ -47,36 +97,44 @@ impl Room {
         format!("user:{}:room", user_id)
     }
 
-    pub(crate) fn key(
+    fn key_draft_str(user_id: i32) -> String {
+        format!("user:{}:draft", user_id)
+    }
+
+    fn key_handle_str(handle: u32) -> String {
+        format!("room_handle:{}", handle)
+    }
+
+    pub(crate) async fn key(
         room_id: &String,
-        redis: &mut redis::Connection,
+        redis: &mut RedisConnection,
     ) -> redis::RedisResult<String> {
         let k = Room::key_str(room_id);
-        redis.expire(&k, 2592000)?;
+        let _: () = redis.expire(&k, settings().storage.ttl).await?;
 
         Ok(k)
     }
 
-    pub(crate) fn key_user(
+    pub(crate) async fn key_user(
         user_id: i32,
-        redis: &mut redis::Connection,
+        redis: &mut RedisConnection,
     ) -> redis::RedisResult<String> {
         let k = Room::key_user_str(user_id);
-        redis.expire(&k, 2592000)?;
+        let _: () = redis.expire(&k, settings().storage.ttl).await?;
 
         Ok(k)
     }
 
-    pub(crate) fn room_users(
+    pub(crate) async fn room_users(
         room_id: &String,
-        redis: &mut redis::Connection,
-    ) -> Result<Option<(Option<i32>, Option<i32>)>, redis::RedisError> {
-        let room_key = Room::key(room_id, redis)?;
+        redis: &mut RedisConnection,
+    ) -> Result<Option<RoomUsers>, redis::RedisError> {
+        let room_key = Room::key(room_id, redis).await?;
         let room_users: Option<Vec<Option<i32>>> =
-            redis.hget(room_key, &["creator_id", "visitor_id"])?;
+            redis.hget(room_key, &["creator_id", "visitor_id"]).await?;
 
-        if room_users.is_some() {
-            let res = room_users.unwrap().try_into().unwrap_or([None, None]);
+        if let Some(room_users) = room_users {
+            let res = room_users.try_into().unwrap_or([None, None]);
             match res {
                 [None, None] => Ok(None),
                 [creator, visitor] => Ok(Some((creator, visitor))),
@@ -86,90 +144,217 @@ impl Room {
         }
     }
 
-    pub(crate) fn create(
+    /// Short number of the room used in callback data instead of the room ID.
+    /// Rooms get one when the first rating button is created.
+    pub(crate) async fn handle(
+        room_id: &String,
+        redis: &mut RedisConnection,
+    ) -> redis::RedisResult<u32> {
+        let room_key = Room::key(room_id, redis).await?;
+        let handle: Option<u32> = redis.hget(&room_key, "handle").await?;
+
+        match handle {
+            Some(handle) => {
+                let _: () = redis
+                    .expire(Room::key_handle_str(handle), settings().storage.ttl)
+                    .await?;
+                Ok(handle)
+            }
+            None => {
+                let handle: u32 = redis.incr(RedisKeys::ROOM_HANDLES, 1).await?;
+                let _: () = redis
+                    .set_ex(
+                        Room::key_handle_str(handle),
+                        room_id,
+                        settings().storage.ttl,
+                    )
+                    .await?;
+                let _: () = redis.hset(&room_key, "handle", handle).await?;
+
+                Ok(handle)
+            }
+        }
+    }
+
+    pub(crate) async fn by_handle(
+        handle: u32,
+        redis: &mut RedisConnection,
+    ) -> redis::RedisResult<Option<String>> {
+        redis.get(Room::key_handle_str(handle)).await
+    }
+
+    /// Number of the question the role is answering now, in async rooms each role has its own.
+    pub(crate) async fn question_idx(
+        room_id: &String,
+        role: &str,
+        redis: &mut RedisConnection,
+    ) -> redis::RedisResult<Option<u16>> {
+        let idx_field = ternary!(
+            RoomMode::get(room_id, redis).await? == RoomMode::ASYNC,
+            format!("{}_idx", role),
+            "idx".to_string()
+        );
+        let room_key = Room::key(room_id, redis).await?;
+
+        match redis.hget(&room_key, &idx_field).await? {
+            Some(idx) => Ok(Some(idx)),
+            None if redis.exists(&room_key).await? => Ok(Some(0)),
+            None => Ok(None),
+        }
+    }
+
+    pub(crate) async fn reset_draft(
+        user_id: i32,
+        redis: &mut RedisConnection,
+    ) -> redis::RedisResult<()> {
+        redis.del(Room::key_draft_str(user_id)).await
+    }
+
+    pub(crate) async fn set_draft(
         user_id: i32,
-        pack: &String,
-        redis: &mut redis::Connection,
+        field: &str,
+        value: &str,
+        redis: &mut RedisConnection,
+    ) -> redis::RedisResult<()> {
+        let draft_key = Room::key_draft_str(user_id);
+        let _: () = redis.hset(&draft_key, field, value).await?;
+        redis.expire(&draft_key, settings().storage.ttl).await
+    }
+
+    pub(crate) async fn draft(
+        user_id: i32,
+        field: &str,
+        redis: &mut RedisConnection,
+    ) -> redis::RedisResult<Option<String>> {
+        redis.hget(Room::key_draft_str(user_id), field).await
+    }
+
+    /// Users currently in the room, the creator goes first.
+    pub(crate) async fn user_ids(
+        room_id: &String,
+        redis: &mut RedisConnection,
+    ) -> Result<Vec<i32>, redis::RedisError> {
+        let users = match Room::room_users(room_id, redis).await? {
+            Some((creator, visitor)) => creator.into_iter().chain(visitor).collect(),
+            None => vec![],
+        };
+
+        Ok(users)
+    }
+
+    pub(crate) async fn create(
+        user_id: i32,
+        draft: &RoomDraft,
+        redis: &mut RedisConnection,
     ) -> Result<String, redis::RedisError> {
         let room_id = random_id();
-        let room_key = Room::key(&room_id, redis)?;
-
-        redis.hset_multiple(
-            room_key,
-            &[
-                ("room_id", room_id.to_string()),
-                ("creator_id", user_id.to_string()),
-                ("pack", pack.to_string()),
-                ("created_at", current_time().to_string()),
-                ("idx", "0".to_string()),
-            ],
-        )?;
+        let room_key = Room::key(&room_id, redis).await?;
+        let order_fields = QuestionOrder::room_fields(&draft.order, &draft.pack, redis).await?;
+
+        let _: () = redis
+            .hset_multiple(
+                &room_key,
+                &[
+                    ("room_id", room_id.to_string()),
+                    ("creator_id", user_id.to_string()),
+                    ("pack", draft.pack.to_string()),
+                    ("mode", draft.mode.to_string()),
+                    ("timer", draft.timer.to_string()),
+                    ("created_at", current_time().to_string()),
+                    ("idx", "0".to_string()),
+                ],
+            )
+            .await?;
+        let _: () = redis.hset_multiple(&room_key, &order_fields).await?;
+
+        if let Some(language) = &draft.language {
+            let _: () = redis.hset(&room_key, "language", language).await?;
+        }
 
+        RoomEvent::record(RoomEvent::CREATED);
         Ok(room_id)
     }
 
-    pub(crate) fn enter(
+    /// Joins the user to the room as the visitor, false if someone has joined it first.
+    pub(crate) async fn enter(
         room_id: &String,
         user_id: i32,
-        redis: &mut redis::Connection,
-    ) -> Result<(), redis::RedisError> {
-        let room_key = Room::key(room_id, redis)?;
-        redis.hset(room_key, "visitor_id", user_id.to_string())?;
-
-        Ok(())
+        redis: &mut RedisConnection,
+    ) -> Result<bool, redis::RedisError> {
+        let room_key = Room::key(room_id, redis).await?;
+        JOIN_ROOM
+            .key(room_key)
+            .arg(user_id)
+            .invoke_async(redis)
+            .await
     }
 
-    pub(crate) fn prepare_for_next_question(
+    pub(crate) async fn prepare_for_next_question(
         room_id: &String,
-        redis: &mut redis::Connection,
+        redis: &mut RedisConnection,
     ) -> Result<u16, redis::RedisError> {
-        let key = Room::key(room_id, redis)?;
-        redis.hdel(
-            &key,
-            &[
-                "visitor_importance",
-                "visitor_evaluation",
-                "visitor_ready_at",
-                "creator_importance",
-                "creator_evaluation",
-                "creator_ready_at",
-            ],
-        )?;
-        let new_idx: u16 = redis.hincr(&key, "idx", 1)?;
+        QuestionOrder::record_scores(room_id, redis).await?;
+
+        let key = Room::key(room_id, redis).await?;
+        let _: () = redis
+            .hdel(
+                &key,
+                &[
+                    "visitor_importance",
+                    "visitor_evaluation",
+                    "visitor_guess",
+                    "visitor_ready_at",
+                    "creator_importance",
+                    "creator_evaluation",
+                    "creator_guess",
+                    "creator_note",
+                    "creator_ready_at",
+                    "question_started_at",
+                ],
+            )
+            .await?;
+        let new_idx: u16 = redis.hincr(&key, "idx", 1).await?;
+
+        let users = Room::user_ids(room_id, redis).await?;
+        QUESTIONS_ANSWERED.inc_by(users.len() as u64);
+
         Ok(new_idx)
     }
 
-    fn set_current_room(
+    async fn set_current_room(
         user_id: i32,
         room_id: &String,
         role: &str,
-        redis: &mut redis::Connection,
+        redis: &mut RedisConnection,
     ) -> Result<(), redis::RedisError> {
-        let user_key = Room::key_user(user_id, redis)?;
-        redis.hset_multiple(user_key, &[("id", room_id), ("role", &role.to_string())])
+        let user_key = Room::key_user(user_id, redis).await?;
+        redis
+            .hset_multiple(user_key, &[("id", room_id), ("role", &role.to_string())])
+            .await
     }
 
     pub(crate) async fn start(
         room_id: &String,
-        redis: &mut redis::Connection,
+        redis: &mut RedisConnection,
         client: &Client,
         url: &str,
         ch_url: &String,
-    ) -> Result<(), Box<dyn std::error::Error>> {
-        let room_key = Room::key(room_id, redis)?;
-        let (creator_id, visitor_id, pack): (i32, i32, String) =
-            redis.hget(room_key, &["creator_id", "visitor_id", "pack"])?;
+    ) -> BotResult<()> {
+        let user_ids = Room::user_ids(room_id, redis).await?;
 
-        Context::set_context(creator_id, Context::IN_ROOM, redis)?;
-        Context::set_context(visitor_id, Context::IN_ROOM, redis)?;
+        for (&user_id, &role) in user_ids.iter().zip([Role::CREATOR, Role::VISITOR].iter()) {
+            Context::set_context(user_id, Context::IN_ROOM, redis).await?;
+            Room::set_current_room(user_id, room_id, role, redis).await?;
+        }
 
-        Room::set_current_room(creator_id, room_id, Role::CREATOR, redis)?;
-        Room::set_current_room(visitor_id, room_id, Role::VISITOR, redis)?;
+        // rooms which go on from another one don't start from the first question
+        let room_key = Room::key(room_id, redis).await?;
+        let idx: Option<u16> = redis.hget(&room_key, "idx").await?;
 
+        RoomEvent::record(RoomEvent::STARTED);
         send_question_messages(
-            [creator_id, visitor_id],
-            &pack,
-            0,
+            &user_ids,
+            idx.unwrap_or(0),
             redis,
             client,
             url,
@@ -185,74 +370,104 @@ impl Room {
         user_id: i32,
         room_id: &String,
         role: &str,
-        redis: &mut redis::Connection,
+        redis: &mut RedisConnection,
         client: &Client,
-        url: &String,
-    ) -> Result<Option<OutgoingKeyboardMessage>, Box<dyn std::error::Error>> {
-        let repeat_question_message = QuestionMessage::get_by_room_id(room_id, redis)?;
-        Room::set_current_room(user_id, room_id, role, redis)?;
-
-        log::info!("{:?}", repeat_question_message);
-        if repeat_question_message.is_some() {
-            repeat_question_message
-                .unwrap()
-                .send(user_id, room_id, redis, client, url)
-                .await?;
+        url: &str,
+    ) -> BotResult<Option<OutgoingKeyboardMessage>> {
+        let repeat_question_message = QuestionMessage::get_by_room_id(room_id, role, redis).await?;
+        Room::set_current_room(user_id, room_id, role, redis).await?;
+
+        tracing::info!(role, question = ?repeat_question_message, "Returning to the room");
+        // the question message sets the context which fits the ratings of the user
+        match repeat_question_message {
+            Some(repeat_question_message) => {
+                repeat_question_message
+                    .send(user_id, room_id, redis, client, url)
+                    .await?
+            }
+            None => Context::set_context(user_id, Context::IN_ROOM, redis).await?,
         }
 
-        Context::set_context(user_id, Context::IN_ROOM, redis)?;
         Ok(None)
     }
 
     pub(crate) async fn write_data(
         room_id: &String,
-        redis: &mut redis::Connection,
+        redis: &mut RedisConnection,
         client: &Client,
         ch_url: &String,
-    ) -> Result<(), Box<dyn std::error::Error>> {
-        let room_key = Room::key(room_id, redis)?;
-        let room: HashMap<String, String> = redis.hgetall(room_key)?;
-
-        let creator_id: i32 = get_parse_string_value(&room, "creator_id", 0);
-        let visitor_id: i32 = get_parse_string_value(&room, "visitor_id", 0);
-        let created_at: i32 = get_parse_string_value(&room, "created_at", 0);
-        let idx: u16 = get_parse_string_value(&room, "idx", 0);
-        let creator_importance: i8 = get_parse_string_value(&room, "creator_importance", 0);
-        let creator_evaluation: i8 = get_parse_string_value(&room, "creator_evaluation", 0);
-        let visitor_importance: i8 = get_parse_string_value(&room, "visitor_importance", 0);
-        let visitor_evaluation: i8 = get_parse_string_value(&room, "visitor_evaluation", 0);
-        let creator_ready_at: i32 = get_parse_string_value(&room, "creator_ready_at", 0);
-        let visitor_ready_at: i32 = get_parse_string_value(&room, "visitor_ready_at", 0);
-
-        let query = format!(
+    ) -> BotResult<()> {
+        let room_key = Room::key(room_id, redis).await?;
+        let room: HashMap<String, String> = redis.hgetall(room_key).await?;
+
+        if room.get("mode").map(|x| x.as_str()) == Some(RoomMode::SOLO) {
+            return SoloRun::write_data(room_id, &room, redis, client, ch_url).await;
+        }
+
+        let query = Room::insert_query(&[Room::row_values(room_id, &room)]);
+
+        analytics::query(client, ch_url, query, "room_insert").await?;
+        Ok(())
+    }
+
+    pub(crate) fn insert_query(rows: &[String]) -> String {
+        format!(
             r#"
-            INSERT INTO tg_room_bot (room_id, creator_id, visitor_id, pack, created_at, idx, creator_importance, creator_evaluation, visitor_importance, visitor_evaluation, creator_ready_at, visitor_ready_at)
-            VALUES ('{room_id}', {creator_id}, {visitor_id}, '{pack}', {created_at}, {idx}, {creator_importance}, {creator_evaluation}, {visitor_importance}, {visitor_evaluation}, {creator_ready_at}, {visitor_ready_at})
+            INSERT INTO tg_room_bot (room_id, creator_id, visitor_id, pack, mode, created_at, idx, creator_importance, creator_evaluation, creator_guess, visitor_importance, visitor_evaluation, visitor_guess, creator_ready_at, visitor_ready_at, question_started_at, timer)
+            VALUES {}
         "#,
+            rows.join(", ")
+        )
+    }
+
+    /// One `tg_room_bot` row built from the room fields of a single question.
+    pub(crate) fn row_values(room_id: &String, room: &HashMap<String, String>) -> String {
+        let creator_id: i32 = get_parse_string_value(room, "creator_id", 0);
+        let visitor_id: i32 = get_parse_string_value(room, "visitor_id", 0);
+        let created_at: i32 = get_parse_string_value(room, "created_at", 0);
+        let idx: u16 = get_parse_string_value(room, "idx", 0);
+        let creator_importance: i8 = get_parse_string_value(room, "creator_importance", 0);
+        let creator_evaluation: i8 = get_parse_string_value(room, "creator_evaluation", 0);
+        let visitor_importance: i8 = get_parse_string_value(room, "visitor_importance", 0);
+        let visitor_evaluation: i8 = get_parse_string_value(room, "visitor_evaluation", 0);
+        let creator_guess: i8 = get_parse_string_value(room, "creator_guess", -1);
+        let visitor_guess: i8 = get_parse_string_value(room, "visitor_guess", -1);
+        let creator_ready_at: i32 = get_parse_string_value(room, "creator_ready_at", 0);
+        let visitor_ready_at: i32 = get_parse_string_value(room, "visitor_ready_at", 0);
+        let question_started_at: i32 = get_parse_string_value(room, "question_started_at", 0);
+        let timer: u16 = get_parse_string_value(room, "timer", 0);
+
+        format!(
+            "('{room_id}', {creator_id}, {visitor_id}, '{pack}', '{mode}', {created_at}, {idx}, {creator_importance}, {creator_evaluation}, {creator_guess}, {visitor_importance}, {visitor_evaluation}, {visitor_guess}, {creator_ready_at}, {visitor_ready_at}, {question_started_at}, {timer})",
             room_id = room_id,
             creator_id = creator_id,
             visitor_id = visitor_id,
             pack = room.get("pack").unwrap_or("".to_string().borrow()),
+            mode = room
+                .get("mode")
+                .map(|x| x.as_str())
+                .unwrap_or(RoomMode::CLASSIC),
             created_at = created_at,
             idx = idx,
             creator_importance = creator_importance,
             creator_evaluation = creator_evaluation,
             visitor_importance = visitor_importance,
             visitor_evaluation = visitor_evaluation,
+            creator_guess = creator_guess,
+            visitor_guess = visitor_guess,
             creator_ready_at = creator_ready_at,
-            visitor_ready_at = visitor_ready_at
-        );
-
-        client.post(ch_url).body(query).send().await?;
-        Ok(())
+            visitor_ready_at = visitor_ready_at,
+            question_started_at = question_started_at,
+            timer = timer
+        )
     }
 
-    pub(crate) fn get_role_for_user(
+    pub(crate) async fn get_role_for_user(
         user_id: i32,
         room_id: &String,
-        redis: &mut redis::Connection,
+        redis: &mut RedisConnection,
     ) -> Result<Option<&'static str>, redis::RedisError> {
-        let role = match Room::room_users(room_id, redis)? {
+        let role = match Room::room_users(room_id, redis).await? {
             Some((Some(creator_id), _)) if user_id == creator_id => Some(Role::CREATOR),
             Some((_, Some(visitor_id))) if user_id == visitor_id => Some(Role::VISITOR),
             _ => None,
@@ -261,17 +476,60 @@ impl Room {
         Ok(role)
     }
 
-    pub(crate) fn clear(
-        creator_id: i32,
-        visitor_id: i32,
+    pub(crate) async fn clear(
+        user_ids: &[i32],
         room_id: &String,
-        redis: &mut redis::Connection,
+        redis: &mut RedisConnection,
     ) -> redis::RedisResult<()> {
-        redis.del(Room::key_user_str(creator_id))?;
-        redis.del(Room::key_user_str(visitor_id))?;
-        Context::reset(creator_id, redis)?;
-        Context::reset(visitor_id, redis)?;
-        redis.del(Room::key_str(room_id))
+        for &user_id in user_ids {
+            let _: () = redis.del(Room::key_user_str(user_id)).await?;
+            let _: () = redis.del(Room::key_draft_str(user_id)).await?;
+            Context::reset(user_id, redis).await?;
+        }
+
+        let _: () = redis.del(&QuestionOrder::keys(room_id)).await?;
+        let _: () = redis.del(&AsyncRoom::keys(room_id)).await?;
+        QuestionTimer::stop(room_id, redis).await?;
+
+        let handle: Option<u32> = redis.hget(Room::key_str(room_id), "handle").await?;
+        if let Some(handle) = handle {
+            let _: () = redis.del(Room::key_handle_str(handle)).await?;
+        }
+
+        redis.del(Room::key_str(room_id)).await
+    }
+}
+
+/// Room settings collected from the creator before the room is created.
+#[derive(Debug, PartialEq)]
+pub struct RoomDraft {
+    pub(crate) pack: String,
+    pub(crate) mode: String,
+    pub(crate) order: String,
+    pub(crate) timer: u64,
+    pub(crate) language: Option<String>,
+}
+
+impl RoomDraft {
+    pub async fn get(
+        user_id: i32,
+        redis: &mut RedisConnection,
+    ) -> Result<Option<RoomDraft>, redis::RedisError> {
+        let draft: HashMap<String, String> = redis.hgetall(Room::key_draft_str(user_id)).await?;
+
+        Ok(draft.get("pack").map(|pack| RoomDraft {
+            pack: pack.to_string(),
+            mode: draft
+                .get("mode")
+                .cloned()
+                .unwrap_or_else(|| RoomMode::CLASSIC.to_string()),
+            order: draft
+                .get("order")
+                .cloned()
+                .unwrap_or_else(|| QuestionOrder::SEQUENTIAL.to_string()),
+            timer: get_parse_string_value(&draft, "timer", 0),
+            language: draft.get("language").cloned(),
+        }))
     }
 }
 
@@ -282,53 +540,93 @@ pub struct UserRoom {
 }
 
 impl UserRoom {
-    pub fn get(user_id: i32, redis: &mut redis::Connection) -> Result<UserRoom, redis::RedisError> {
-        let user_key = Room::key_user(user_id, redis)?;
-        let role: HashMap<String, String> = redis.hgetall(user_key)?;
+    pub(crate) async fn room_id(
+        user_id: i32,
+        redis: &mut RedisConnection,
+    ) -> redis::RedisResult<Option<String>> {
+        redis.hget(Room::key_user_str(user_id), "id").await
+    }
 
-        Ok(UserRoom {
-            id: (&role.get("id").unwrap()).to_string(),
-            role: (&role.get("role").unwrap()).to_string(),
-        })
+    pub async fn get(user_id: i32, redis: &mut RedisConnection) -> BotResult<UserRoom> {
+        let user_key = Room::key_user(user_id, redis).await?;
+        let role: HashMap<String, String> = redis.hgetall(user_key).await?;
+
+        match (role.get("id"), role.get("role")) {
+            (Some(id), Some(role)) => Ok(UserRoom {
+                id: id.to_string(),
+                role: role.to_string(),
+            }),
+            _ => Err(BotError::State(format!(
+                "user {} is not in a room",
+                user_id
+            ))),
+        }
     }
 
-    pub(crate) fn set_ready_time(
+    /// Sets `{role}_{step}` to the current time. True only for the call which completes the step
+    /// for the room, i.e. the partner is done too or there is no partner.
+    pub(crate) async fn mark_done(
         &self,
-        redis: &mut redis::Connection,
+        step: &str,
+        has_partner: bool,
+        redis: &mut RedisConnection,
     ) -> Result<bool, redis::RedisError> {
-        let key = Room::key(&self.id, redis)?;
-        let role_field = format!("{}_ready_at", self.role);
-        let opposite_role_field = format!("{}_ready_at", Role::opposite(&self.role));
-        let is_already_set: bool = redis.hexists(&key, &role_field)?;
+        let key = Room::key(&self.id, redis).await?;
+        let role_field = format!("{}_{}", self.role, step);
+        let opposite_role_field = ternary!(
+            has_partner,
+            format!("{}_{}", Role::opposite(&self.role), step),
+            String::new()
+        );
 
-        if !is_already_set {
-            redis.hset(&key, &role_field, current_time().to_string())?
-        }
+        MARK_DONE
+            .key(key)
+            .arg(role_field)
+            .arg(current_time())
+            .arg(opposite_role_field)
+            .invoke_async(redis)
+            .await
+    }
 
-        if redis.hexists(&key, &opposite_role_field)? {
-            Ok(true)
-        } else {
-            Ok(false)
-        }
+    /// Takes back `{role}_{step}` when the room failed to move on, so that the next press retries.
+    pub(crate) async fn undo_done(
+        &self,
+        step: &str,
+        redis: &mut RedisConnection,
+    ) -> Result<(), redis::RedisError> {
+        let key = Room::key(&self.id, redis).await?;
+        redis.hdel(key, format!("{}_{}", self.role, step)).await
+    }
+
+    /// True if the room should move on to the next question.
+    pub(crate) async fn set_ready_time(
+        &self,
+        redis: &mut RedisConnection,
+    ) -> Result<bool, redis::RedisError> {
+        let is_solo = RoomMode::get(&self.id, redis).await? == RoomMode::SOLO;
+        self.mark_done("ready_at", !is_solo, redis).await
     }
 }
 
 pub struct Context;
 impl Context {
-    pub fn get(user_id: i32, redis: &mut redis::Connection) -> redis::RedisResult<String> {
-        redis.get(Context::key(user_id))
+    pub async fn get(user_id: i32, redis: &mut RedisConnection) -> redis::RedisResult<String> {
+        let context: Option<String> = redis.get(Context::key(user_id)).await?;
+        Ok(context.unwrap_or_default())
     }
 
-    pub(crate) fn set_context(
+    pub(crate) async fn set_context(
         user_id: i32,
         context: &'static str,
-        redis: &mut redis::Connection,
+        redis: &mut RedisConnection,
     ) -> redis::RedisResult<()> {
-        redis.set(Context::key(user_id), context)
+        redis
+            .set_ex(Context::key(user_id), context, settings().storage.ttl)
+            .await
     }
 
-    pub(crate) fn reset(user_id: i32, redis: &mut redis::Connection) -> redis::RedisResult<()> {
-        redis.del(Context::key(user_id))
+    pub(crate) async fn reset(user_id: i32, redis: &mut RedisConnection) -> redis::RedisResult<()> {
+        redis.del(Context::key(user_id)).await
     }
 
     pub(crate) fn key(user_id: i32) -> String {
@@ -336,9 +634,55 @@ impl Context {
     }
 
     pub const SELECT_PACK: &'static str = "SELECT_PACK";
+    pub const SELECT_PACK_LANGUAGE: &'static str = "SELECT_PACK_LANGUAGE";
+    pub const SELECT_ORDER: &'static str = "SELECT_ORDER";
+    pub const SELECT_TIMER: &'static str = "SELECT_TIMER";
     pub const INSERT_ID: &'static str = "INSERT_ID";
     pub const WAITING_FOR_PARTNER: &'static str = "WAITING_FOR_PARTNER";
     pub const WAITING_FOR_ANSWER: &'static str = "WAITING_FOR_ANSWER";
     pub const IN_ROOM: &'static str = "IN_ROOM";
     pub const WAITING_FOR_RESULTS: &'static str = "WAITING_FOR_RESULTS";
+    pub const PARTNER_UNREACHABLE: &'static str = "PARTNER_UNREACHABLE";
+}
+
+/// Users who have blocked the bot. The bot does not write to them until they send something again.
+pub struct Unreachable;
+impl Unreachable {
+    fn key_str(user_id: i32) -> String {
+        format!("user:{}:unreachable", user_id)
+    }
+
+    pub(crate) async fn mark(user_id: i32, redis: &mut RedisConnection) -> redis::RedisResult<()> {
+        redis
+            .set_ex(
+                Unreachable::key_str(user_id),
+                current_time(),
+                settings().storage.ttl,
+            )
+            .await
+    }
+
+    pub(crate) async fn clear(user_id: i32, redis: &mut RedisConnection) -> redis::RedisResult<()> {
+        redis.del(Unreachable::key_str(user_id)).await
+    }
+
+    pub(crate) async fn is(user_id: i32, redis: &mut RedisConnection) -> redis::RedisResult<bool> {
+        redis.exists(Unreachable::key_str(user_id)).await
+    }
+
+    /// Users of the list the bot can write to.
+    pub(crate) async fn reachable(
+        user_ids: &[i32],
+        redis: &mut RedisConnection,
+    ) -> redis::RedisResult<Vec<i32>> {
+        let mut reachable = vec![];
+
+        for &user_id in user_ids {
+            if !Unreachable::is(user_id, redis).await? {
+                reachable.push(user_id);
+            }
+        }
+
+        Ok(reachable)
+    }
 }
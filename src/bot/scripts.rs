@@ -0,0 +1,199 @@
+use lazy_static::lazy_static;
+use redis::Script;
+
+const SET_RATING_LUA: &str = include_str!("../../scripts/set_rating.lua");
+const MARK_DONE_LUA: &str = include_str!("../../scripts/mark_done.lua");
+const JOIN_ROOM_LUA: &str = include_str!("../../scripts/join_room.lua");
+
+lazy_static! {
+    /// Room transitions which read and then write the room, run atomically in Redis.
+    /// Sources live in `scripts/*.lua`.
+    pub(crate) static ref SET_RATING: Script = Script::new(SET_RATING_LUA);
+    pub(crate) static ref MARK_DONE: Script = Script::new(MARK_DONE_LUA);
+    pub(crate) static ref JOIN_ROOM: Script = Script::new(JOIN_ROOM_LUA);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use mlua::{Lua, Variadic};
+    use std::collections::HashMap;
+    use std::sync::{Arc, Barrier, Mutex};
+    use std::thread;
+
+    const ROOM: &str = "room:test";
+
+    /// Hashes of an in-memory Redis, which like Redis runs one script at a time
+    /// on the Lua version it embeds.
+    #[derive(Default)]
+    struct Hashes(Mutex<HashMap<String, HashMap<String, String>>>);
+
+    impl Hashes {
+        fn with_room(fields: &[(&str, &str)]) -> Arc<Hashes> {
+            let room = fields
+                .iter()
+                .map(|(field, value)| (field.to_string(), value.to_string()))
+                .collect();
+            let hashes = Hashes::default();
+            hashes.0.lock().unwrap().insert(ROOM.to_string(), room);
+            Arc::new(hashes)
+        }
+
+        fn field(&self, field: &str) -> Option<String> {
+            self.0.lock().unwrap().get(ROOM)?.get(field).cloned()
+        }
+
+        fn eval(&self, source: &str, args: &[&str]) -> i64 {
+            let mut hashes = self.0.lock().unwrap();
+            let lua = Lua::new();
+
+            lua.scope(|scope| {
+                let call = scope.create_function_mut(|_, args: Variadic<String>| {
+                    if args[0] == "EXISTS" {
+                        return Ok(hashes.contains_key(&args[1]) as i64);
+                    }
+
+                    let hash = hashes.entry(args[1].clone()).or_default();
+                    let exists = hash.contains_key(&args[2]);
+
+                    Ok(match args[0].as_str() {
+                        "HEXISTS" => exists as i64,
+                        "HSETNX" if exists => 0,
+                        "HSETNX" | "HSET" => {
+                            hash.insert(args[2].clone(), args[3].clone());
+                            !exists as i64
+                        }
+                        command => panic!("{} is not supported", command),
+                    })
+                })?;
+                let redis = lua.create_table()?;
+                redis.set("call", call)?;
+
+                let globals = lua.globals();
+                globals.set("redis", redis)?;
+                globals.set("KEYS", vec![ROOM])?;
+                globals.set("ARGV", args.to_vec())?;
+
+                lua.load(source).eval::<i64>()
+            })
+            .unwrap()
+        }
+    }
+
+    /// Runs the scripts with the arguments at once and returns the results in the same order.
+    fn race(hashes: &Arc<Hashes>, source: &'static str, calls: Vec<Vec<String>>) -> Vec<i64> {
+        let barrier = Arc::new(Barrier::new(calls.len()));
+
+        calls
+            .into_iter()
+            .map(|args| {
+                let hashes = hashes.clone();
+                let barrier = barrier.clone();
+                thread::spawn(move || {
+                    let args: Vec<&str> = args.iter().map(|x| x.as_str()).collect();
+                    barrier.wait();
+                    hashes.eval(source, &args)
+                })
+            })
+            .collect::<Vec<_>>()
+            .into_iter()
+            .map(|handle| handle.join().unwrap())
+            .collect()
+    }
+
+    fn args(args: &[&str]) -> Vec<String> {
+        args.iter().map(|x| x.to_string()).collect()
+    }
+
+    fn winners(results: &[i64]) -> Vec<usize> {
+        (0..results.len()).filter(|&i| results[i] == 1).collect()
+    }
+
+    #[test]
+    fn one_visitor_joins() {
+        for _ in 0..50 {
+            let hashes = Hashes::with_room(&[("creator_id", "1")]);
+            let calls = (2..10).map(|id| args(&[&id.to_string()])).collect();
+
+            let results = race(&hashes, JOIN_ROOM_LUA, calls);
+            let winners = winners(&results);
+
+            assert_eq!(winners.len(), 1, "{:?}", results);
+            assert_eq!(
+                hashes.field("visitor_id"),
+                Some((winners[0] + 2).to_string())
+            );
+        }
+    }
+
+    #[test]
+    fn nobody_joins_a_missing_room() {
+        let hashes = Arc::new(Hashes::default());
+        assert_eq!(race(&hashes, JOIN_ROOM_LUA, vec![args(&["2"])]), vec![0]);
+    }
+
+    #[test]
+    fn room_advances_once() {
+        for _ in 0..50 {
+            let hashes = Hashes::with_room(&[]);
+            // both partners press Next twice
+            let calls = vec![
+                args(&["creator_ready_at", "1", "visitor_ready_at"]),
+                args(&["visitor_ready_at", "2", "creator_ready_at"]),
+                args(&["creator_ready_at", "3", "visitor_ready_at"]),
+                args(&["visitor_ready_at", "4", "creator_ready_at"]),
+            ];
+
+            let results = race(&hashes, MARK_DONE_LUA, calls);
+            assert_eq!(winners(&results).len(), 1, "{:?}", results);
+        }
+    }
+
+    #[test]
+    fn room_without_partner_advances_once() {
+        let hashes = Hashes::with_room(&[]);
+        let calls = vec![args(&["creator_ready_at", "1", ""]); 4];
+
+        let results = race(&hashes, MARK_DONE_LUA, calls);
+        assert_eq!(winners(&results).len(), 1, "{:?}", results);
+    }
+
+    #[test]
+    fn ratings_complete_once() {
+        for _ in 0..50 {
+            let hashes = Hashes::with_room(&[]);
+            let fields = ["creator_importance", "creator_evaluation"];
+            let calls = (0..6)
+                .map(|i| {
+                    args(&[
+                        fields[i % 2],
+                        &(i % 5).to_string(),
+                        "0",
+                        fields[0],
+                        fields[1],
+                    ])
+                })
+                .collect();
+
+            let results = race(&hashes, SET_RATING_LUA, calls);
+            assert_eq!(winners(&results).len(), 1, "{:?}", results);
+        }
+    }
+
+    #[test]
+    fn one_guess_is_set() {
+        for _ in 0..50 {
+            let hashes = Hashes::with_room(&[]);
+            let calls = (0..5)
+                .map(|i| args(&["creator_guess", &i.to_string(), "1", "creator_guess"]))
+                .collect();
+
+            let results = race(&hashes, SET_RATING_LUA, calls);
+            let winners = winners(&results);
+
+            assert_eq!(winners.len(), 1, "{:?}", results);
+            assert_eq!(results.iter().filter(|&&x| x == -1).count(), 4);
+            assert_eq!(hashes.field("creator_guess"), Some(winners[0].to_string()));
+        }
+    }
+}
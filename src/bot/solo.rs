@@ -0,0 +1,178 @@
+use crate::analytics;
+use crate::bot::constants::*;
+use crate::bot::locale::Locale;
+use crate::bot::order::QuestionOrder;
+use crate::bot::pack::Pack;
+use crate::bot::report::{SoloComparisonData, SoloReportData};
+use crate::bot::room::*;
+use crate::error::BotResult;
+use crate::settings::settings;
+use crate::storage::RedisConnection;
+use crate::tools::*;
+
+use redis::AsyncCommands;
+use reqwest::Client;
+use std::collections::HashMap;
+
+/// A solo room: one user rates how important each topic of a pack is to them and may leave notes.
+/// Finished runs are kept so that they can be compared with a partner's run of the same pack.
+pub struct SoloRun;
+impl SoloRun {
+    fn key_str(run_id: &str) -> String {
+        format!("solo:{}", run_id)
+    }
+
+    fn key_user_str(user_id: i32) -> String {
+        format!("user:{}:solo", user_id)
+    }
+
+    pub(crate) fn is_valid_id(run_id: &str) -> bool {
+        run_id.len() == 16 && run_id.chars().all(|x| x.is_ascii_alphanumeric())
+    }
+
+    pub(crate) async fn write_data(
+        room_id: &String,
+        room: &HashMap<String, String>,
+        redis: &mut RedisConnection,
+        client: &Client,
+        ch_url: &String,
+    ) -> BotResult<()> {
+        let idx: u16 = get_parse_string_value(room, "idx", 0);
+        let position = QuestionOrder::position(room_id, idx, redis)
+            .await?
+            .unwrap_or(idx);
+
+        let query = format!(
+            r#"
+            INSERT INTO tg_solo_runs (run_id, user_id, pack, created_at, idx, position, importance, note, ready_at)
+            VALUES ('{run_id}', {user_id}, '{pack}', {created_at}, {idx}, {position}, {importance}, '{note}', {ready_at})
+        "#,
+            run_id = room_id,
+            user_id = get_parse_string_value(room, "creator_id", 0),
+            pack = escape_string(room.get("pack").map(|x| x.as_str()).unwrap_or("")),
+            created_at = get_parse_string_value(room, "created_at", 0),
+            idx = idx,
+            position = position,
+            importance = get_parse_string_value::<i8>(room, "creator_importance", 0),
+            note = escape_string(room.get("creator_note").map(|x| x.as_str()).unwrap_or("")),
+            ready_at = get_parse_string_value(room, "creator_ready_at", 0)
+        );
+
+        analytics::query(client, ch_url, query, "solo_insert").await?;
+        Ok(())
+    }
+
+    pub(crate) async fn add_note(
+        room_id: &String,
+        note: &str,
+        redis: &mut RedisConnection,
+    ) -> redis::RedisResult<()> {
+        let room_key = Room::key(room_id, redis).await?;
+        let previous: Option<String> = redis.hget(&room_key, "creator_note").await?;
+        let note = match previous {
+            Some(previous) => format!("{}\n{}", previous, note),
+            None => note.to_string(),
+        };
+
+        redis.hset(room_key, "creator_note", note).await
+    }
+
+    /// Remembers the finished run as the user's latest run of the pack.
+    pub(crate) async fn finish(
+        user_id: i32,
+        room_id: &String,
+        redis: &mut RedisConnection,
+    ) -> redis::RedisResult<()> {
+        let room_key = Room::key(room_id, redis).await?;
+        let pack: String = redis.hget(room_key, "pack").await?;
+        let run_key = SoloRun::key_str(room_id);
+        let user_key = SoloRun::key_user_str(user_id);
+
+        let _: () = redis
+            .hset_multiple(
+                &run_key,
+                &[("user_id", user_id.to_string()), ("pack", pack.to_string())],
+            )
+            .await?;
+        let _: () = redis.hset(&user_key, &pack, room_id).await?;
+        let _: () = redis.expire(&run_key, settings().storage.ttl).await?;
+        redis.expire(&user_key, settings().storage.ttl).await
+    }
+
+    pub(crate) async fn report(
+        room_id: &String,
+        locale: Locale,
+        redis: &mut RedisConnection,
+        client: &Client,
+        ch_url: &String,
+    ) -> BotResult<String> {
+        let report = SoloReportData::get(room_id, client, ch_url).await?;
+        let room_key = Room::key(room_id, redis).await?;
+        let room: HashMap<String, String> = redis.hgetall(room_key).await?;
+        let pack = room.get("pack").cloned().unwrap_or_default();
+        let mut locales = vec![locale];
+        locales.extend(room.get("language").and_then(|x| Locale::from_code(x)));
+        let top_questions =
+            SoloRun::questions(&pack, report.top_positions(), &locales, redis).await?;
+
+        Ok(report.generate_report(room_id, &top_questions, locale))
+    }
+
+    pub(crate) async fn compare(
+        user_id: i32,
+        other_run_id: &str,
+        locale: Locale,
+        redis: &mut RedisConnection,
+        client: &Client,
+        ch_url: &String,
+    ) -> BotResult<String> {
+        if !SoloRun::is_valid_id(other_run_id) {
+            return Ok(locale
+                .text(Messages::ERROR_SOLO_RUN_DOES_NOT_EXIST)
+                .to_string());
+        }
+
+        let other_run: HashMap<String, String> =
+            redis.hgetall(SoloRun::key_str(other_run_id)).await?;
+        let pack = match other_run.get("pack") {
+            Some(pack) => pack,
+            None => {
+                return Ok(locale
+                    .text(Messages::ERROR_SOLO_RUN_DOES_NOT_EXIST)
+                    .to_string())
+            }
+        };
+
+        let own_run_id: Option<String> = redis.hget(SoloRun::key_user_str(user_id), pack).await?;
+        let own_run_id = match own_run_id {
+            Some(own_run_id) if own_run_id != other_run_id => own_run_id,
+            _ => {
+                return Ok(locale.format(
+                    Messages::ERROR_NO_OWN_SOLO_RUN,
+                    &[("pack", pack.to_string())],
+                ))
+            }
+        };
+
+        let comparison = SoloComparisonData::get(&own_run_id, other_run_id, client, ch_url).await?;
+        let diverging_questions =
+            SoloRun::questions(pack, comparison.diverging_positions(), &[locale], redis).await?;
+
+        Ok(comparison.generate_report(&diverging_questions, locale))
+    }
+
+    async fn questions(
+        pack: &str,
+        positions: &[u16],
+        locales: &[Locale],
+        redis: &mut RedisConnection,
+    ) -> redis::RedisResult<Vec<String>> {
+        let mut questions = vec![];
+
+        for &position in positions {
+            questions.extend(Pack::question(pack, position, locales, redis).await?);
+        }
+
+        Ok(questions)
+    }
+}
@@ -0,0 +1,257 @@
+use crate::bot::constants::*;
+use crate::bot::handlers::Handlers;
+use crate::bot::locale::Locale;
+use crate::bot::room::*;
+use crate::error::BotResult;
+use crate::settings::settings;
+use crate::storage::RedisConnection;
+use crate::telegram::helpers::create_tg_url;
+use crate::telegram::messages::send_message;
+use crate::telegram::structures::*;
+use crate::ternary;
+use crate::tools::*;
+
+use redis::AsyncCommands;
+use reqwest::Client;
+use std::collections::HashMap;
+use std::time::Duration;
+use tokio::sync::watch;
+
+/// Discussion countdown of the current question of a room.
+/// Running timers are kept in the `timers` sorted set scored by the time of their next update.
+pub struct QuestionTimer;
+impl QuestionTimer {
+    /// Discussion time in seconds of the timer buttons, 0 is no timer.
+    pub(crate) fn options() -> Vec<u64> {
+        let minutes = settings().timer.options.iter().map(|minutes| minutes * 60);
+        std::iter::once(0).chain(minutes).collect()
+    }
+
+    pub(crate) fn label(seconds: u64, locale: Locale) -> String {
+        match seconds {
+            0 => locale.text(Keys::TIMER_NONE).to_string(),
+            _ => locale.format(
+                Keys::TIMER_MINUTES,
+                &[("minutes", (seconds / 60).to_string())],
+            ),
+        }
+    }
+
+    pub fn from_key(text: &str) -> Option<u64> {
+        QuestionTimer::options().into_iter().find(|&seconds| {
+            Locale::ALL
+                .iter()
+                .any(|&locale| QuestionTimer::label(seconds, locale) == text)
+        })
+    }
+
+    fn key_str(room_id: &str) -> String {
+        format!("room:{}:timer", room_id)
+    }
+
+    fn format_remaining(seconds: u64, locale: Locale) -> String {
+        locale.format(
+            Messages::TIME_LEFT,
+            &[
+                ("minutes", (seconds / 60).to_string()),
+                ("seconds", format!("{:02}", seconds % 60)),
+            ],
+        )
+    }
+
+    /// Marks the start of the question and, if the room is timed, starts its countdown.
+    pub(crate) async fn start(
+        room_id: &String,
+        idx: u16,
+        redis: &mut RedisConnection,
+    ) -> redis::RedisResult<()> {
+        let room_key = Room::key(room_id, redis).await?;
+        let now = current_time();
+        let timer: Option<u64> = redis.hget(&room_key, "timer").await?;
+        let _: () = redis.hset(&room_key, "question_started_at", now).await?;
+
+        let timer_key = QuestionTimer::key_str(room_id);
+        let _: () = redis.del(&timer_key).await?;
+
+        match timer {
+            Some(timer) if timer > 0 => {
+                let _: () = redis
+                    .hset_multiple(
+                        &timer_key,
+                        &[("idx", idx as u64), ("deadline", now + timer)],
+                    )
+                    .await?;
+                let _: () = redis.expire(&timer_key, settings().storage.ttl).await?;
+                redis
+                    .zadd(
+                        RedisKeys::TIMERS,
+                        room_id,
+                        now + settings().timer.tick.min(timer),
+                    )
+                    .await
+            }
+            _ => redis.zrem(RedisKeys::TIMERS, room_id).await,
+        }
+    }
+
+    /// Sends the countdown message to the user if the current question of the room is timed.
+    pub(crate) async fn send_countdown(
+        user_id: i32,
+        room_id: &str,
+        redis: &mut RedisConnection,
+        client: &Client,
+        url: &str,
+    ) -> BotResult<()> {
+        let timer_key = QuestionTimer::key_str(room_id);
+        let deadline: Option<u64> = redis.hget(&timer_key, "deadline").await?;
+
+        if let Some(deadline) = deadline {
+            let remaining = deadline.saturating_sub(current_time());
+            let locale = Locale::get(user_id, redis).await?;
+            let message = OutgoingKeyboardMessage::with_text(
+                user_id,
+                &QuestionTimer::format_remaining(remaining, locale),
+            );
+            let message_id = send_message(url, &message, client).await?;
+            let _: () = redis
+                .hset(&timer_key, format!("message:{}", user_id), message_id)
+                .await?;
+        }
+
+        Ok(())
+    }
+
+    /// Updates countdowns which are due until `stopped` is set. A tick in progress is completed.
+    pub(crate) async fn run(
+        mut redis: RedisConnection,
+        client: Client,
+        bot_token: String,
+        mut stopped: watch::Receiver<bool>,
+    ) {
+        loop {
+            tokio::select! {
+                _ = tokio::time::sleep(Duration::from_secs(settings().timer.poll_interval)) => {}
+                _ = stopped.changed() => return,
+            }
+
+            if let Err(e) = QuestionTimer::tick(&mut redis, &client, &bot_token).await {
+                e.log();
+            }
+        }
+    }
+
+    async fn tick(redis: &mut RedisConnection, client: &Client, bot_token: &str) -> BotResult<()> {
+        let due: Vec<String> = redis
+            .zrangebyscore(RedisKeys::TIMERS, 0, current_time())
+            .await?;
+
+        let url = create_tg_url(bot_token, TgMethods::SEND_MESSAGE);
+
+        for room_id in due.iter() {
+            // a failed room should not stop the countdowns of the others
+            let error = match QuestionTimer::update(room_id, redis, client, bot_token).await {
+                Ok(()) => continue,
+                Err(e) => e,
+            };
+            error.log();
+
+            if let Some(chat_id) = error.blocked_chat() {
+                if let Err(e) = Handlers::unreachable(chat_id, client, redis, &url).await {
+                    e.log();
+                }
+            }
+        }
+
+        Ok(())
+    }
+
+    async fn update(
+        room_id: &String,
+        redis: &mut RedisConnection,
+        client: &Client,
+        bot_token: &str,
+    ) -> BotResult<()> {
+        let timer_key = QuestionTimer::key_str(room_id);
+        let timer: HashMap<String, String> = redis.hgetall(&timer_key).await?;
+        let room_key = Room::key(room_id, redis).await?;
+        let room_idx: Option<u16> = redis.hget(&room_key, "idx").await?;
+        let timer_idx: Option<u16> = timer.get("idx").and_then(|x| x.parse().ok());
+
+        // the room has moved on or does not exist anymore
+        if room_idx.is_none() || room_idx != timer_idx {
+            QuestionTimer::stop(room_id, redis).await?;
+            return Ok(());
+        }
+
+        let now = current_time();
+        let remaining = get_parse_string_value(&timer, "deadline", now).saturating_sub(now);
+
+        for (field, message_id) in timer.iter() {
+            let chat_id = field.strip_prefix("message:").and_then(|x| x.parse().ok());
+
+            if let (Some(chat_id), Ok(message_id)) = (chat_id, message_id.parse()) {
+                if Unreachable::is(chat_id as i32, redis).await? {
+                    continue;
+                }
+
+                let locale = Locale::get(chat_id as i32, redis).await?;
+                let text = ternary!(
+                    remaining == 0,
+                    locale.text(Messages::TIME_IS_UP).to_string(),
+                    QuestionTimer::format_remaining(remaining, locale)
+                );
+
+                EditedMessageText::new(chat_id, message_id, &text)
+                    .edit(bot_token, client)
+                    .await?;
+            }
+        }
+
+        if remaining > 0 {
+            let _: () = redis
+                .zadd(
+                    RedisKeys::TIMERS,
+                    room_id,
+                    now + settings().timer.tick.min(remaining),
+                )
+                .await?;
+            return Ok(());
+        }
+
+        QuestionTimer::stop(room_id, redis).await?;
+
+        let url = create_tg_url(bot_token, TgMethods::SEND_MESSAGE);
+        let users = Room::room_users(room_id, redis)
+            .await?
+            .unwrap_or((None, None));
+
+        for (user_id, role) in [(users.0, Role::CREATOR), (users.1, Role::VISITOR)].iter() {
+            if let Some(user_id) = user_id {
+                if Unreachable::is(*user_id, redis).await? {
+                    continue;
+                }
+
+                if !CallbackData::role_has_all_callback_keys(&role.to_string(), room_id, redis)
+                    .await?
+                {
+                    let locale = Locale::get(*user_id, redis).await?;
+                    let message = OutgoingKeyboardMessage::with_text(
+                        *user_id,
+                        locale.text(Messages::RATE_AFTER_TIMER),
+                    );
+                    send_message(&url, &message, client).await?;
+                }
+            }
+        }
+
+        Ok(())
+    }
+
+    pub(crate) async fn stop(
+        room_id: &String,
+        redis: &mut RedisConnection,
+    ) -> redis::RedisResult<()> {
+        let _: () = redis.zrem(RedisKeys::TIMERS, room_id).await?;
+        redis.del(QuestionTimer::key_str(room_id)).await
+    }
+}
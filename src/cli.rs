@@ -0,0 +1,216 @@
+use crate::bot::constants::RedisKeys;
+use crate::bot::pack::Pack;
+use crate::bot::room::*;
+use crate::error::{BotError, BotResult};
+use crate::migrations::{migrate, migrate_analytics, scan_keys};
+use crate::settings::{settings, Required};
+use crate::storage::RedisConnection;
+
+use clap::{Parser, Subcommand};
+use redis::AsyncCommands;
+use reqwest::Client;
+use std::collections::{BTreeMap, HashMap};
+
+/// Telegram bot which sends questions to discuss to couples.
+#[derive(Parser, Debug)]
+#[command(version)]
+pub struct Cli {
+    #[command(subcommand)]
+    pub command: Option<Command>,
+}
+
+#[derive(Subcommand, Debug)]
+pub enum Command {
+    /// Run the bot, the default
+    Run,
+    /// List the question packs with their languages and sizes
+    Packs,
+    /// Inspect or close rooms
+    Rooms {
+        #[command(subcommand)]
+        command: RoomsCommand,
+    },
+    /// Manage the state of users
+    Users {
+        #[command(subcommand)]
+        command: UsersCommand,
+    },
+    /// Count rooms, users and running timers
+    Stats,
+    /// Apply pending migrations of the data in Redis and the ClickHouse schema
+    Migrate,
+}
+
+impl Command {
+    /// Operator commands work with Redis, only the bot and the migrations need more.
+    pub fn required(&self) -> Required {
+        Required {
+            telegram: matches!(self, Command::Run),
+            analytics: matches!(self, Command::Run | Command::Migrate),
+        }
+    }
+}
+
+#[derive(Subcommand, Debug)]
+pub enum RoomsCommand {
+    /// Show the fields and the users of a room
+    Inspect { room_id: String },
+    /// Close a room, its users are back at the start
+    Close { room_id: String },
+}
+
+#[derive(Subcommand, Debug)]
+pub enum UsersCommand {
+    /// Bring a user back to the start, closing their room
+    Reset { user_id: i32 },
+}
+
+/// Runs an operator command, everything but `Run`.
+pub async fn execute(command: Command, redis: &mut RedisConnection) -> BotResult<()> {
+    match command {
+        Command::Run => unreachable!("the bot is run by main"),
+        Command::Packs => packs(redis).await,
+        Command::Rooms {
+            command: RoomsCommand::Inspect { room_id },
+        } => inspect_room(&room_id, redis).await,
+        Command::Rooms {
+            command: RoomsCommand::Close { room_id },
+        } => close_room(&room_id, redis).await,
+        Command::Users {
+            command: UsersCommand::Reset { user_id },
+        } => reset_user(user_id, redis).await,
+        Command::Stats => stats(redis).await,
+        Command::Migrate => {
+            let applied = migrate(redis).await?;
+
+            if applied.is_empty() {
+                println!("No pending migrations");
+            }
+            for description in applied {
+                println!("Applied: {}", description);
+            }
+
+            let statements = migrate_analytics(&Client::new(), &settings().analytics.url).await?;
+            println!(
+                "ClickHouse schema is up to date, {} statements run",
+                statements
+            );
+
+            Ok(())
+        }
+    }
+}
+
+async fn packs(redis: &mut RedisConnection) -> BotResult<()> {
+    for pack in Pack::names(redis).await? {
+        let languages: Vec<&str> = Pack::languages(&pack, redis)
+            .await?
+            .iter()
+            .map(|x| x.code())
+            .collect();
+        let len = Pack::len(&pack, redis).await?;
+
+        println!("{}\t{} questions\t{}", pack, len, languages.join(", "));
+    }
+
+    Ok(())
+}
+
+/// Reads the room as is, without extending its lifetime as the bot does on every access.
+async fn room_fields(
+    room_id: &String,
+    redis: &mut RedisConnection,
+) -> BotResult<HashMap<String, String>> {
+    let room: HashMap<String, String> = redis.hgetall(Room::key_str(room_id)).await?;
+
+    if room.is_empty() {
+        return Err(BotError::State(format!("room {} does not exist", room_id)));
+    }
+
+    Ok(room)
+}
+
+async fn inspect_room(room_id: &String, redis: &mut RedisConnection) -> BotResult<()> {
+    let room: BTreeMap<String, String> = room_fields(room_id, redis).await?.into_iter().collect();
+
+    for (field, value) in room.iter() {
+        println!("{}: {}", field, value);
+    }
+
+    let user_ids = ["creator_id", "visitor_id"]
+        .iter()
+        .filter_map(|field| room.get(*field)?.parse::<i32>().ok());
+
+    for user_id in user_ids {
+        let context = Context::get(user_id, redis).await?;
+        let unreachable = Unreachable::is(user_id, redis).await?;
+
+        println!(
+            "user {}: context {}{}",
+            user_id,
+            context,
+            if unreachable { ", unreachable" } else { "" }
+        );
+    }
+
+    Ok(())
+}
+
+async fn close_room(room_id: &String, redis: &mut RedisConnection) -> BotResult<()> {
+    room_fields(room_id, redis).await?;
+
+    let users = Room::user_ids(room_id, redis).await?;
+    Room::clear(&users, room_id, redis).await?;
+
+    println!("Closed room {}, users: {:?}", room_id, users);
+    Ok(())
+}
+
+async fn reset_user(user_id: i32, redis: &mut RedisConnection) -> BotResult<()> {
+    match UserRoom::room_id(user_id, redis).await? {
+        Some(room_id) => close_room(&room_id, redis).await?,
+        None => {
+            Room::reset_draft(user_id, redis).await?;
+            Context::reset(user_id, redis).await?;
+        }
+    }
+
+    Unreachable::clear(user_id, redis).await?;
+
+    println!("Reset user {}", user_id);
+    Ok(())
+}
+
+async fn stats(redis: &mut RedisConnection) -> BotResult<()> {
+    let mut modes: BTreeMap<String, u32> = BTreeMap::new();
+
+    // `room:{id}` only, not the keys of the room parts such as `room:{id}:timer`
+    for key in scan_keys("room:*", redis).await? {
+        if key.matches(':').count() == 1 {
+            let mode: Option<String> = redis.hget(&key, "mode").await?;
+            *modes
+                .entry(mode.unwrap_or_else(|| RoomMode::CLASSIC.to_string()))
+                .or_insert(0) += 1;
+        }
+    }
+
+    let rooms: u32 = modes.values().sum();
+    println!("rooms: {}", rooms);
+    for (mode, count) in modes.iter() {
+        println!("  {}: {}", mode, count);
+    }
+
+    let users = scan_keys("user:*:context", redis).await?.len();
+    let in_rooms = scan_keys("user:*:room", redis).await?.len();
+    let unreachable = scan_keys("user:*:unreachable", redis).await?.len();
+    let timers: usize = redis.zcard(RedisKeys::TIMERS).await?;
+    let packs = Pack::names(redis).await?.len();
+
+    println!("users with a state: {}", users);
+    println!("users in rooms: {}", in_rooms);
+    println!("unreachable users: {}", unreachable);
+    println!("running timers: {}", timers);
+    println!("packs: {}", packs);
+
+    Ok(())
+}
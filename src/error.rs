@@ -0,0 +1,107 @@
+use crate::bot::constants::Messages;
+use crate::telegram::sender::{network_failure, SendError};
+
+use redis::RedisError;
+use std::fmt;
+use tracing::Level;
+
+pub type BotResult<T> = Result<T, BotError>;
+
+#[derive(Debug)]
+pub enum BotError {
+    Redis(RedisError),
+    Telegram(SendError),
+    /// ClickHouse has failed to store the answers or to build a report.
+    Analytics(String),
+    /// The user has sent something the bot can't accept, holds the message to reply with.
+    Validation(&'static str),
+    /// Stored data does not fit the update, e.g. the room has been closed meanwhile.
+    State(String),
+    /// The bot can't go on, e.g. Telegram has rejected the token.
+    Fatal(String),
+}
+
+impl fmt::Display for BotError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            BotError::Redis(e) => write!(f, "Redis error: {}", e),
+            BotError::Telegram(e) => write!(f, "{}", e),
+            BotError::Analytics(e) => write!(f, "ClickHouse error: {}", e),
+            BotError::Validation(key) => write!(f, "Invalid input: {}", key),
+            BotError::State(e) => write!(f, "Unexpected state: {}", e),
+            BotError::Fatal(e) => write!(f, "Fatal error: {}", e),
+        }
+    }
+}
+
+impl std::error::Error for BotError {}
+
+impl From<RedisError> for BotError {
+    fn from(e: RedisError) -> BotError {
+        BotError::Redis(e)
+    }
+}
+
+impl From<SendError> for BotError {
+    fn from(e: SendError) -> BotError {
+        BotError::Telegram(e)
+    }
+}
+
+// Telegram requests go through `sender`, so plain HTTP requests are the ones to ClickHouse
+impl From<reqwest::Error> for BotError {
+    fn from(e: reqwest::Error) -> BotError {
+        // the ClickHouse url may carry the credentials
+        BotError::Analytics(network_failure(&e))
+    }
+}
+
+impl From<serde_json::Error> for BotError {
+    fn from(e: serde_json::Error) -> BotError {
+        BotError::Analytics(e.to_string())
+    }
+}
+
+impl BotError {
+    /// Message key of the reply to the user whose update has failed, if they should get one.
+    pub(crate) fn user_message(&self) -> Option<&'static str> {
+        match self {
+            BotError::Redis(_) => Some(Messages::ERROR_INTERNAL),
+            BotError::Telegram(SendError::Blocked(_)) => None,
+            BotError::Telegram(_) => Some(Messages::ERROR_INTERNAL),
+            BotError::Analytics(_) => Some(Messages::ERROR_ANALYTICS),
+            BotError::Validation(key) => Some(key),
+            BotError::State(_) => Some(Messages::ERROR_STATE),
+            BotError::Fatal(_) => None,
+        }
+    }
+
+    pub(crate) fn level(&self) -> Level {
+        match self {
+            BotError::Telegram(SendError::Blocked(_)) => Level::WARN,
+            BotError::Validation(_) => Level::INFO,
+            BotError::State(_) => Level::WARN,
+            _ => Level::ERROR,
+        }
+    }
+
+    pub(crate) fn log(&self) {
+        match self.level() {
+            Level::INFO => tracing::info!("{}", self),
+            Level::WARN => tracing::warn!("{}", self),
+            _ => tracing::error!("{}", self),
+        }
+    }
+
+    /// Chat which has blocked the bot if the error is caused by it.
+    pub(crate) fn blocked_chat(&self) -> Option<i32> {
+        match self {
+            BotError::Telegram(SendError::Blocked(chat_id)) => Some(*chat_id as i32),
+            _ => None,
+        }
+    }
+
+    pub(crate) fn is_fatal(&self) -> bool {
+        matches!(self, BotError::Fatal(_))
+    }
+}
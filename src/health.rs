@@ -0,0 +1,114 @@
+use crate::analytics;
+use crate::error::{BotError, BotResult};
+use crate::settings::settings;
+use crate::storage::RedisConnection;
+use crate::tools::current_time;
+
+use reqwest::Client;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::Duration;
+
+// a poll lasts up to `poll_timeout`, failed ones are retried after `poll_retry_delay`
+const POLL_GRACE: u64 = 60;
+const CHECK_TIMEOUT: u64 = 3;
+
+static LAST_POLL: AtomicU64 = AtomicU64::new(0);
+
+/// Marks the poll loop as alive, on startup and after every successful poll.
+pub fn polled() {
+    LAST_POLL.store(current_time(), Ordering::Relaxed);
+}
+
+/// Result of a probe, one line per check.
+pub struct Report {
+    pub healthy: bool,
+    pub lines: Vec<String>,
+}
+
+impl Report {
+    fn new() -> Report {
+        Report {
+            healthy: true,
+            lines: vec![],
+        }
+    }
+
+    fn check(&mut self, name: &str, result: BotResult<String>) {
+        let line = match result {
+            Ok(details) => format!("{}: ok{}", name, details),
+            // errors may carry urls with credentials, the body is public so they only go to the log
+            Err(e) => {
+                tracing::warn!(check = name, error = %e, "Health check failed");
+                self.healthy = false;
+                format!("{}: failed", name)
+            }
+        };
+
+        self.lines.push(line);
+    }
+}
+
+/// Liveness: the poll loop has got updates from Telegram recently.
+pub fn liveness() -> Report {
+    let mut report = Report::new();
+    report.check("poll", poll());
+    report
+}
+
+/// Readiness: the bot is alive and can store the data of rooms and their answers.
+pub async fn readiness(redis: &mut RedisConnection, client: &Client) -> Report {
+    let mut report = liveness();
+    report.check("redis", with_timeout(redis_ping(redis)).await);
+    report.check("analytics", with_timeout(analytics_writable(client)).await);
+    report
+}
+
+fn poll() -> BotResult<String> {
+    let last_poll = LAST_POLL.load(Ordering::Relaxed);
+    let ago = current_time().saturating_sub(last_poll);
+    let telegram = &settings().telegram;
+
+    if last_poll == 0 {
+        Err(BotError::State("polling has not started".to_string()))
+    } else if ago > telegram.poll_timeout + telegram.poll_retry_delay + POLL_GRACE {
+        Err(BotError::State(format!("last poll was {}s ago", ago)))
+    } else {
+        Ok(format!(", last poll {}s ago", ago))
+    }
+}
+
+async fn redis_ping(redis: &mut RedisConnection) -> BotResult<String> {
+    let _: String = redis::cmd("PING").query_async(redis).await?;
+    Ok(String::new())
+}
+
+/// The user can write and the tables the answers go to exist.
+async fn analytics_writable(client: &Client) -> BotResult<String> {
+    let query = "
+        SELECT getSetting('readonly') = 0
+            AND (SELECT count() FROM system.tables WHERE database = currentDatabase() AND name IN ('tg_room_bot', 'tg_solo_runs')) = 2
+        FORMAT TabSeparated
+    ";
+    let res = analytics::query(
+        client,
+        &settings().analytics.url,
+        query.to_string(),
+        "health",
+    )
+    .await?;
+
+    match String::from_utf8_lossy(&res).trim() {
+        "1" => Ok(String::new()),
+        _ => Err(BotError::Analytics(
+            "the database is read only or its tables are missing".to_string(),
+        )),
+    }
+}
+
+async fn with_timeout<F: std::future::Future<Output = BotResult<String>>>(
+    check: F,
+) -> BotResult<String> {
+    tokio::time::timeout(Duration::from_secs(CHECK_TIMEOUT), check)
+        .await
+        .unwrap_or_else(|_| Err(BotError::State("timed out".to_string())))
+}
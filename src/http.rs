@@ -0,0 +1,69 @@
+use crate::health::{self, Report};
+use crate::metrics;
+use crate::storage::RedisPool;
+use crate::ternary;
+
+use hyper::header::CONTENT_TYPE;
+use hyper::service::{make_service_fn, service_fn};
+use hyper::{Body, Method, Request, Response, Server, StatusCode};
+use reqwest::Client;
+use std::convert::Infallible;
+use std::net::SocketAddr;
+use std::sync::Arc;
+
+/// Serves `/metrics` for Prometheus and the `/healthz` and `/readyz` probes until the process exits.
+pub async fn serve(address: SocketAddr, redis_pool: Arc<RedisPool>, client: Client) {
+    let make_service = make_service_fn(move |_| {
+        let redis_pool = redis_pool.clone();
+        let client = client.clone();
+
+        async move {
+            Ok::<_, Infallible>(service_fn(move |request| {
+                handle(request, redis_pool.clone(), client.clone())
+            }))
+        }
+    });
+
+    tracing::info!(%address, "Serving metrics and probes");
+
+    if let Err(e) = Server::bind(&address).serve(make_service).await {
+        tracing::error!("HTTP server has failed: {}", e);
+    }
+}
+
+async fn handle(
+    request: Request<Body>,
+    redis_pool: Arc<RedisPool>,
+    client: Client,
+) -> Result<Response<Body>, Infallible> {
+    let response = match (request.method(), request.uri().path()) {
+        (&Method::GET, "/metrics") => {
+            let (content_type, body) = metrics::encode();
+            Response::builder()
+                .header(CONTENT_TYPE, content_type)
+                .body(Body::from(body))
+        }
+        (&Method::GET, "/healthz") => probe(health::liveness()),
+        (&Method::GET, "/readyz") => {
+            probe(health::readiness(&mut redis_pool.connection(), &client).await)
+        }
+        _ => Response::builder()
+            .status(StatusCode::NOT_FOUND)
+            .body(Body::empty()),
+    };
+
+    Ok(response.unwrap())
+}
+
+fn probe(report: Report) -> hyper::http::Result<Response<Body>> {
+    let status = ternary!(
+        report.healthy,
+        StatusCode::OK,
+        StatusCode::SERVICE_UNAVAILABLE
+    );
+
+    Response::builder()
+        .status(status)
+        .header(CONTENT_TYPE, "text/plain; charset=utf-8")
+        .body(Body::from(report.lines.join("\n")))
+}
@@ -1,161 +1,570 @@
+mod analytics;
 mod bot;
+mod cli;
+mod error;
+mod health;
+mod http;
+mod metrics;
+mod migrations;
+mod settings;
+mod storage;
 mod telegram;
 mod tools;
 
 use crate::bot::constants::*;
 use crate::bot::handlers::Handlers;
+use crate::bot::locale::Locale;
+use crate::bot::room::{RoomMode, Unreachable, UserRoom};
+use crate::bot::timer::QuestionTimer;
+use crate::cli::{Cli, Command};
+use crate::error::{BotError, BotResult};
+use crate::settings::{settings, Settings};
+use crate::storage::{RedisConnection, RedisPool};
 use crate::telegram::helpers::*;
 use crate::telegram::messages::*;
+use crate::telegram::sender::{self, SendError};
 use crate::telegram::structures::*;
-use crate::tools::read_key_env;
 
-use log::LevelFilter;
-use redis::Commands;
+use clap::Parser;
+use redis::AsyncCommands;
 use reqwest::Client;
-use simple_logger::SimpleLogger;
 use std::borrow::Borrow;
+use std::collections::hash_map::DefaultHasher;
+use std::collections::BTreeSet;
+use std::hash::{Hash, Hasher};
+use std::io::IsTerminal;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tokio::signal::unix::{signal, SignalKind};
+use tokio::sync::{mpsc, watch, Notify};
+use tokio::task::JoinHandle;
+use tracing::{field, Instrument, Span};
+use tracing_subscriber::filter::LevelFilter;
 
 #[tokio::main]
-async fn main() -> Result<(), Box<dyn std::error::Error>> {
-    SimpleLogger::new()
-        .with_level(LevelFilter::Info)
-        .init()
-        .unwrap();
-
-    let token = read_key_env("TG_TOKEN").expect("No TG_TOKEN found!");
-    let ch_url = read_key_env("CH_URL").expect("No CH_URL found!");
+async fn main() {
+    let command = Cli::parse().command.unwrap_or(Command::Run);
+    let settings = match Settings::init(command.required()) {
+        Ok(settings) => settings,
+        Err(e) => {
+            eprintln!("{}", e);
+            std::process::exit(1);
+        }
+    };
+
+    init_logger(settings);
+
+    let result = match command {
+        Command::Run => run(settings).await,
+        command => match RedisPool::connect(&settings.storage).await {
+            Ok(redis_pool) => cli::execute(command, &mut redis_pool.connection()).await,
+            Err(e) => Err(e.into()),
+        },
+    };
+
+    if let Err(e) = result {
+        e.log();
+        std::process::exit(1);
+    }
+}
+
+/// Records of `log`, e.g. of the dependencies, go through the same subscriber.
+fn init_logger(settings: &Settings) {
+    let logger = tracing_subscriber::fmt()
+        .with_max_level(settings.log_level().unwrap_or(LevelFilter::INFO))
+        .with_target(false)
+        .with_ansi(std::io::stdout().is_terminal());
+
+    if settings.log_format == "json" {
+        logger.json().init();
+    } else {
+        logger.init();
+    }
+}
+
+async fn run(settings: &'static Settings) -> BotResult<()> {
+    let token = &settings.telegram.token;
+    let ch_url = &settings.analytics.url;
     let client = reqwest::Client::new();
-    let mut redis = redis::Client::open(read_key_env("REDIS").unwrap())?.get_connection()?;
+    let redis_pool = RedisPool::connect(&settings.storage).await?;
+    let mut redis = redis_pool.connection();
+    let workers_count = settings.limits.workers;
+    let shutdown_timeout = settings.limits.shutdown_timeout;
+    let handled_update_id: i32 = redis
+        .get::<_, Option<i32>>(RedisKeys::LATEST_MESSAGE)
+        .await?
+        .unwrap_or(0);
+    let progress = Arc::new(Progress::new(handled_update_id));
+    let workers = Workers::spawn(
+        workers_count,
+        &redis_pool,
+        &progress,
+        token,
+        &client,
+        ch_url,
+    );
+    let (stop_timer, timer_stopped) = watch::channel(false);
+
+    let timer = tokio::spawn(QuestionTimer::run(
+        redis_pool.connection(),
+        client.clone(),
+        token.to_string(),
+        timer_stopped,
+    ));
+
+    if let Some(address) = settings.http.address() {
+        tokio::spawn(http::serve(address, redis_pool.clone(), client.clone()));
+    }
+    tokio::spawn(metrics::count_active_rooms(redis_pool.connection()));
+
+    tracing::info!(workers = workers_count, "Started the bot");
+
+    let result = longpoll(token, &client, &mut redis, &workers, &progress).await;
 
-    log::info!("Started the bot");
+    // polling has stopped, the updates taken from Telegram are handled before exiting
+    let _ = stop_timer.send(true);
+    let finished = tokio::time::timeout(Duration::from_secs(shutdown_timeout), async {
+        workers.finish().await;
+        let _ = timer.await;
+    })
+    .await;
 
-    longpoll(&token, &client, &mut redis, &ch_url).await
+    if finished.is_err() {
+        tracing::warn!("Shutdown timeout has passed, queued updates are handled after restart");
+    }
+
+    let handled_update_id = progress.handled();
+    if let Err(e) = confirm_updates(token, &client, &mut redis, handled_update_id).await {
+        e.log();
+    }
+
+    tracing::info!(update_id = handled_update_id, "Stopped the bot");
+
+    result
 }
 
-async fn get_updates(
+/// Updates taken from Telegram and not handled yet. Updates are handled out of order by the workers,
+/// so only the updates before the first unhandled one are confirmed, and the ones still queued
+/// on a crash or a shutdown come again on restart.
+struct Progress {
+    state: Mutex<ProgressState>,
+    finished: Notify,
+}
+
+struct ProgressState {
+    pending: BTreeSet<i32>,
+    dispatched: i32,
+}
+
+impl Progress {
+    fn new(handled: i32) -> Progress {
+        Progress {
+            state: Mutex::new(ProgressState {
+                pending: BTreeSet::new(),
+                dispatched: handled,
+            }),
+            finished: Notify::new(),
+        }
+    }
+
+    /// Updates before the first unhandled one come again with every poll and are skipped.
+    fn is_new(&self, update_id: i32) -> bool {
+        update_id > self.state.lock().unwrap().dispatched
+    }
+
+    fn start(&self, update_id: i32) {
+        let mut state = self.state.lock().unwrap();
+        state.pending.insert(update_id);
+        state.dispatched = state.dispatched.max(update_id);
+    }
+
+    fn finish(&self, update_id: i32) {
+        self.state.lock().unwrap().pending.remove(&update_id);
+        self.finished.notify_one();
+    }
+
+    /// The latest update which has been handled together with all updates before it.
+    fn handled(&self) -> i32 {
+        let state = self.state.lock().unwrap();
+        match state.pending.iter().next() {
+            Some(first) => first - 1,
+            None => state.dispatched,
+        }
+    }
+}
+
+/// Queues of updates handled concurrently. Updates of one room, or of one user outside of rooms,
+/// always get into the same queue, so they are handled in the order they came.
+struct Workers {
+    senders: Vec<mpsc::Sender<TgUpdate>>,
+    handles: Vec<JoinHandle<()>>,
+}
+
+impl Workers {
+    fn spawn(
+        count: usize,
+        redis_pool: &Arc<RedisPool>,
+        progress: &Arc<Progress>,
+        bot_token: &str,
+        client: &Client,
+        ch_url: &str,
+    ) -> Workers {
+        let mut senders = vec![];
+        let mut handles = vec![];
+
+        for _ in 0..count.max(1) {
+            let (sender, receiver) = mpsc::channel(settings().limits.worker_queue);
+            handles.push(tokio::spawn(worker(
+                receiver,
+                redis_pool.connection(),
+                progress.clone(),
+                bot_token.to_string(),
+                client.clone(),
+                ch_url.to_string(),
+            )));
+            senders.push(sender);
+        }
+
+        Workers { senders, handles }
+    }
+
+    /// Closes the queues and waits until the workers handle the updates left in them.
+    async fn finish(self) {
+        drop(self.senders);
+
+        for handle in self.handles {
+            if let Err(e) = handle.await {
+                tracing::error!("{:?}", e);
+            }
+        }
+    }
+
+    /// Falls back to the queue of the user while Redis is unavailable.
+    async fn shard(&self, update: &TgUpdate, redis: &mut RedisConnection) -> usize {
+        let mut hasher = DefaultHasher::new();
+
+        if let Some(user) = update.from() {
+            match UserRoom::room_id(user.id, redis).await {
+                Ok(Some(room_id)) => room_id.hash(&mut hasher),
+                Ok(None) => user.id.hash(&mut hasher),
+                Err(e) => {
+                    tracing::error!("{:?}", e);
+                    user.id.hash(&mut hasher)
+                }
+            }
+        }
+
+        hasher.finish() as usize % self.senders.len()
+    }
+
+    /// Waits while the queue of the update is full.
+    async fn dispatch(&self, update: TgUpdate, redis: &mut RedisConnection) -> BotResult<()> {
+        let shard = self.shard(&update, redis).await;
+        self.senders[shard]
+            .send(update)
+            .await
+            .map_err(|_| BotError::Fatal("worker has stopped".to_string()))
+    }
+}
+
+async fn worker(
+    mut receiver: mpsc::Receiver<TgUpdate>,
+    mut redis: RedisConnection,
+    progress: Arc<Progress>,
+    bot_token: String,
+    client: Client,
+    ch_url: String,
+) {
+    let url = create_tg_url(&bot_token, TgMethods::SEND_MESSAGE);
+
+    while let Some(update) = receiver.recv().await {
+        process_update(&update, &bot_token, &client, &mut redis, &ch_url, &url)
+            .instrument(update_span(&update))
+            .await;
+        progress.finish(update.update_id);
+    }
+}
+
+/// Span of everything logged while handling the update, `room_id` and `update_type`
+/// are recorded once they are known.
+fn update_span(update: &TgUpdate) -> Span {
+    let span = tracing::info_span!(
+        "update",
+        update_id = update.update_id,
+        user_id = field::Empty,
+        room_id = field::Empty,
+        update_type = field::Empty,
+    );
+
+    if let Some(user) = update.from() {
+        span.record("user_id", user.id);
+    }
+
+    span
+}
+
+async fn process_update(
+    update: &TgUpdate,
     bot_token: &str,
     client: &Client,
-    latest_update_id: i32,
-) -> Result<TgResult, Box<dyn std::error::Error>> {
-    let url = create_tg_url(bot_token, TgMethods::GET_UPDATES);
-    let offset: String = format!("{}", latest_update_id + 1);
-    let params: [(&str, &str); 2] = [("offset", &offset), ("timeout", "60")];
-
-    let res = client
-        .get(&url)
-        .query(&params)
-        .send()
-        .await?
-        .bytes()
-        .await?;
+    redis: &mut RedisConnection,
+    ch_url: &String,
+    url: &str,
+) {
+    let error = match handle_updates(update, bot_token, client, redis, ch_url, url).await {
+        Ok(()) => return,
+        Err(e) => e,
+    };
+    error.log();
 
-    let tg_response = serde_json::from_slice::<TgResult>(&res);
+    // the user gets the offer to go on alone instead of the error
+    if let Some(chat_id) = error.blocked_chat() {
+        if let Err(e) = Handlers::unreachable(chat_id, client, redis, url).await {
+            e.log();
+        }
+    } else if let (Some(key), Some(user)) = (error.user_message(), update.from()) {
+        let locale = Locale::get(user.id, redis).await.unwrap_or(Locale::DEFAULT);
+        let message = OutgoingKeyboardMessage::with_text(user.id, locale.text(key));
 
-    if tg_response.is_err() {
-        match serde_json::from_slice::<TgError>(&res) {
-            Ok(tg_error) => log::error!("{:?}", tg_error),
-            Err(err) => log::error!("{:?}", err),
+        if let Err(e) = send_message(url, &message, client).await {
+            tracing::error!("{}", e);
         }
     }
+}
 
-    Ok(tg_response?)
+async fn get_updates(
+    bot_token: &str,
+    client: &Client,
+    handled_update_id: i32,
+) -> BotResult<Vec<TgUpdate>> {
+    let url = create_tg_url(bot_token, TgMethods::GET_UPDATES);
+    let params = serde_json::json!({ "offset": handled_update_id + 1, "timeout": settings().telegram.poll_timeout });
+
+    match sender::request(&url, &params, client).await {
+        // the token is wrong or revoked
+        Err(SendError::Api { code, description }) if code == 401 || code == 404 => {
+            Err(BotError::Fatal(description))
+        }
+        result => Ok(result?),
+    }
 }
 
 async fn handle_updates(
     update: &TgUpdate,
     bot_token: &str,
     client: &Client,
-    redis: &mut redis::Connection,
+    redis: &mut RedisConnection,
     ch_url: &String,
-    url: &String,
-) -> Result<(), Box<dyn std::error::Error>> {
-    let message_type = update.handle_message_type(redis)?;
+    url: &str,
+) -> BotResult<()> {
     let message = update.message.borrow();
 
-    if message.is_some() {
-        log::info!("{:?}", message.as_ref().unwrap());
+    if let Some(message) = message {
+        message.log();
+    }
+
+    if let Some(TgUser {
+        id,
+        language_code: Some(language_code),
+        ..
+    }) = update.from()
+    {
+        Locale::remember(*id, language_code, redis).await?;
+    }
+
+    // whoever writes to the bot has unblocked it
+    if let Some(user) = update.from() {
+        Unreachable::clear(user.id, redis).await?;
     }
 
-    let chat_id = message.as_ref().map(|x| x.from.id);
+    let message_type = update.handle_message_type(redis).await?;
+    let span = Span::current();
+    span.record("update_type", message_type.name());
+    metrics::UPDATES
+        .with_label_values(&[message_type.name()])
+        .inc();
+    let chat_id = update.from().map(|x| x.id);
+
+    if let Some(user_id) = chat_id {
+        if let Some(room_id) = UserRoom::room_id(user_id, redis).await? {
+            span.record("room_id", room_id.as_str());
+        }
+
+        let locale = Locale::get(user_id, redis).await?;
 
-    match &message_type {
-        UpdateType::Callback(chat, message, d, id) => {
+        if let UpdateType::Callback(chat, message, d, id) = &message_type {
             d.handle_callback(id, *chat, *message, redis, client, bot_token)
                 .await?;
+        } else if let Some(query) = &update.callback_query {
+            match message_type {
+                UpdateType::InvalidCallback => {
+                    let text = locale.text(Messages::CALLBACK_INVALID);
+                    query.reject(text, bot_token, client).await?
+                }
+                _ => query.acknowledge(bot_token, client).await?,
+            }
         }
-        _ => (),
-    }
-
-    if chat_id.is_some() {
-        let user_id = chat_id.unwrap();
 
         let response: Option<OutgoingKeyboardMessage> = match message_type {
-            UpdateType::Start => Some(OutgoingKeyboardMessage::welcome_message(user_id)),
-            UpdateType::JoinExisting => Handlers::join_existing(user_id, redis)?,
-            UpdateType::Create => Handlers::create(user_id, redis)?,
-            UpdateType::NewRoom => Handlers::new_room(user_id, message, redis)?,
+            UpdateType::Start => Some(OutgoingKeyboardMessage::welcome_message(user_id, locale)),
+            UpdateType::JoinExisting => Handlers::join_existing(user_id, locale, redis).await?,
+            UpdateType::Create => {
+                Handlers::create(user_id, RoomMode::CLASSIC, locale, redis).await?
+            }
+            UpdateType::CreateGuess => {
+                Handlers::create(user_id, RoomMode::GUESS, locale, redis).await?
+            }
+            UpdateType::SelectPack(pack) => {
+                Handlers::select_pack(user_id, pack, locale, redis).await?
+            }
+            UpdateType::SelectPackLanguage(language) => {
+                Handlers::select_pack_language(user_id, language, locale, redis).await?
+            }
+            UpdateType::CreateSolo => {
+                Handlers::create(user_id, RoomMode::SOLO, locale, redis).await?
+            }
+            UpdateType::CreateAsync => {
+                Handlers::create(user_id, RoomMode::ASYNC, locale, redis).await?
+            }
+            UpdateType::SelectOrder(order) => {
+                Handlers::select_order(user_id, order, locale, client, redis, url, ch_url).await?
+            }
+            UpdateType::NewRoom(timer) => {
+                Handlers::new_room(user_id, timer, locale, client, redis, url, ch_url).await?
+            }
             UpdateType::InsertId => {
-                Handlers::insert_id(user_id, message, client, redis, url, ch_url).await?
+                Handlers::insert_id(user_id, message, locale, client, redis, url, ch_url).await?
             }
             UpdateType::WaitingForOther => {
                 Handlers::waiting_for_answer(user_id, client, redis, url, ch_url).await?
             }
+            UpdateType::Note => Handlers::note(user_id, message, locale, redis).await?,
+            UpdateType::Compare => {
+                Handlers::compare(user_id, message, locale, client, redis, ch_url).await?
+            }
+            UpdateType::Language => Handlers::language(user_id, message, locale, redis).await?,
             UpdateType::WaitingForResults => Some(OutgoingKeyboardMessage::with_text(
                 user_id,
-                Messages::WAIT_A_MOMENT,
+                locale.text(Messages::WAIT_A_MOMENT),
+            )),
+            UpdateType::Help => Some(OutgoingKeyboardMessage::with_text(
+                user_id,
+                locale.text(Messages::HELP),
             )),
-            UpdateType::Help => Some(OutgoingKeyboardMessage::with_text(user_id, Messages::HELP)),
+            UpdateType::ContinueAlone => {
+                Handlers::continue_alone(user_id, client, redis, url, ch_url).await?
+            }
+            UpdateType::LeaveRoom => Handlers::leave_room(user_id, locale, redis).await?,
             UpdateType::UnknownCommand => Some(OutgoingKeyboardMessage::with_text(
                 user_id,
-                Messages::ERROR_UNKNOWN_COMMAND,
+                locale.text(Messages::ERROR_UNKNOWN_COMMAND),
             )),
-            UpdateType::Error => Some(OutgoingKeyboardMessage::error(user_id)),
-            _ => Some(OutgoingKeyboardMessage::error(user_id)),
+            UpdateType::Callback(..) | UpdateType::InvalidCallback | UpdateType::Other => None,
+            UpdateType::Error => Some(OutgoingKeyboardMessage::error(user_id, locale)),
         };
 
-        if response.is_some() {
-            send_message(url, &response.unwrap(), client).await?;
+        if let Some(response) = response {
+            send_message(url, &response, client).await?;
         }
     }
 
     Ok(())
 }
 
+/// Passes updates to the workers until SIGTERM or SIGINT.
 async fn longpoll(
     bot_token: &str,
     client: &Client,
-    redis: &mut redis::Connection,
-    ch_url: &String,
-) -> Result<(), Box<dyn std::error::Error>> {
-    let mut latest_update_id: i32 = 0;
-    let url = create_tg_url(bot_token, TgMethods::SEND_MESSAGE);
+    redis: &mut RedisConnection,
+    workers: &Workers,
+    progress: &Progress,
+) -> BotResult<()> {
+    let shutdown = shutdown_signal();
+    tokio::pin!(shutdown);
+    health::polled();
+    let mut saved_update_id = progress.handled();
 
     loop {
-        let updates = get_updates(bot_token, client, latest_update_id).await?;
-
-        for update in updates.result {
-            let upd = handle_updates(&update, bot_token, client, redis, ch_url, &url).await;
+        let handled_update_id = progress.handled();
+        if handled_update_id != saved_update_id {
+            let saved: redis::RedisResult<()> = redis
+                .set(RedisKeys::LATEST_MESSAGE, handled_update_id)
+                .await;
+            match saved {
+                Ok(()) => saved_update_id = handled_update_id,
+                Err(e) => tracing::error!("{:?}", e),
+            }
+        }
 
-            if upd.is_err() {
-                let user_id = &update.message.map(|m| m.from.id);
+        // a poll cut short is fine, its updates are sent again by the next one
+        let polled = tokio::select! {
+            polled = get_updates(bot_token, client, handled_update_id) => polled,
+            _ = &mut shutdown => return Ok(()),
+        };
 
-                if user_id.is_some() {
-                    send_message(
-                        &url,
-                        &OutgoingKeyboardMessage::internal_error(user_id.unwrap()),
-                        client,
-                    )
-                    .await?;
+        let updates = match polled {
+            Ok(updates) => {
+                health::polled();
+                updates
+            }
+            Err(e) if e.is_fatal() => return Err(e),
+            Err(e) => {
+                e.log();
+                tokio::select! {
+                    _ = tokio::time::sleep(Duration::from_secs(settings().telegram.poll_retry_delay)) => continue,
+                    _ = &mut shutdown => return Ok(()),
                 }
+            }
+        };
+
+        let polled_any = !updates.is_empty();
+        let mut dispatched_any = false;
 
-                log::error!("{:?}", upd);
+        for update in updates {
+            if !progress.is_new(update.update_id) {
+                continue;
             }
 
-            latest_update_id = update.update_id;
-            redis.set(RedisKeys::LATEST_MESSAGE, latest_update_id)?;
-            log::info!("Latest update: {}", latest_update_id);
+            let update_id = update.update_id;
+            progress.start(update_id);
+            workers.dispatch(update, redis).await?;
+            dispatched_any = true;
+            tracing::info!(update_id, "Latest update");
+        }
+
+        // the poll has only brought queued updates, so the next one would return them at once
+        if polled_any && !dispatched_any {
+            tokio::select! {
+                _ = progress.finished.notified() => {}
+                _ = &mut shutdown => return Ok(()),
+            }
         }
     }
+}
+
+async fn shutdown_signal() {
+    let mut terminate = signal(SignalKind::terminate()).expect("Failed to listen for SIGTERM");
+
+    tokio::select! {
+        _ = terminate.recv() => tracing::info!("Got SIGTERM, shutting down"),
+        _ = tokio::signal::ctrl_c() => tracing::info!("Got SIGINT, shutting down"),
+    }
+}
+
+/// Saves the offset and confirms the handled updates, so Telegram does not send them again.
+async fn confirm_updates(
+    bot_token: &str,
+    client: &Client,
+    redis: &mut RedisConnection,
+    handled_update_id: i32,
+) -> BotResult<()> {
+    let _: () = redis
+        .set(RedisKeys::LATEST_MESSAGE, handled_update_id)
+        .await?;
+
+    let url = create_tg_url(bot_token, TgMethods::GET_UPDATES);
+    let params = serde_json::json!({ "offset": handled_update_id + 1, "timeout": 0, "limit": 1 });
+    sender::request::<_, Vec<TgUpdate>>(&url, &params, client).await?;
 
     Ok(())
 }
@@ -0,0 +1,107 @@
+use crate::error::BotResult;
+use crate::migrations::scan_keys;
+use crate::storage::RedisConnection;
+
+use lazy_static::lazy_static;
+use prometheus::{
+    register_histogram_vec, register_int_counter, register_int_counter_vec, register_int_gauge,
+    Encoder, HistogramVec, IntCounter, IntCounterVec, IntGauge, TextEncoder,
+};
+use redis::AsyncCommands;
+use std::collections::HashSet;
+use std::time::Duration;
+
+// active rooms are counted by scanning Redis, so not too often
+const ACTIVE_ROOMS_INTERVAL: u64 = 60;
+
+lazy_static! {
+    pub static ref UPDATES: IntCounterVec =
+        register_int_counter_vec!("bot_updates_total", "Updates handled, by type", &["type"])
+            .unwrap();
+    pub static ref ROOMS: IntCounterVec = register_int_counter_vec!(
+        "bot_rooms_total",
+        "Rooms created, started, finished and abandoned",
+        &["event"]
+    )
+    .unwrap();
+    pub static ref QUESTIONS_ANSWERED: IntCounter = register_int_counter!(
+        "bot_questions_answered_total",
+        "Questions a user has finished rating"
+    )
+    .unwrap();
+    pub static ref ACTIVE_ROOMS: IntGauge =
+        register_int_gauge!("bot_active_rooms", "Rooms with users in them").unwrap();
+    pub static ref TELEGRAM_DURATION: HistogramVec = register_histogram_vec!(
+        "bot_telegram_request_duration_seconds",
+        "Telegram API requests, by method",
+        &["method"]
+    )
+    .unwrap();
+    pub static ref TELEGRAM_ERRORS: IntCounterVec = register_int_counter_vec!(
+        "bot_telegram_errors_total",
+        "Failed Telegram API requests, retries included, by method and kind",
+        &["method", "kind"]
+    )
+    .unwrap();
+    pub static ref REDIS_DURATION: HistogramVec = register_histogram_vec!(
+        "bot_redis_command_duration_seconds",
+        "Redis commands and pipelines, by outcome",
+        &["outcome"],
+        vec![0.0005, 0.001, 0.0025, 0.005, 0.01, 0.025, 0.05, 0.1, 0.25, 0.5, 1.0, 5.0]
+    )
+    .unwrap();
+    pub static ref CLICKHOUSE_DURATION: HistogramVec = register_histogram_vec!(
+        "bot_clickhouse_request_duration_seconds",
+        "ClickHouse queries, by query and outcome",
+        &["query", "outcome"]
+    )
+    .unwrap();
+}
+
+pub struct RoomEvent;
+
+impl RoomEvent {
+    pub const CREATED: &'static str = "created";
+    pub const STARTED: &'static str = "started";
+    pub const FINISHED: &'static str = "finished";
+    pub const ABANDONED: &'static str = "abandoned";
+
+    pub fn record(event: &str) {
+        ROOMS.with_label_values(&[event]).inc();
+    }
+}
+
+/// All the metrics in the Prometheus text format.
+pub fn encode() -> (String, Vec<u8>) {
+    let encoder = TextEncoder::new();
+    let mut buffer = vec![];
+
+    if let Err(e) = encoder.encode(&prometheus::gather(), &mut buffer) {
+        tracing::error!("{:?}", e);
+    }
+
+    (encoder.format_type().to_string(), buffer)
+}
+
+/// Keeps the active rooms gauge up to date.
+pub async fn count_active_rooms(mut redis: RedisConnection) {
+    loop {
+        match active_rooms(&mut redis).await {
+            Ok(count) => ACTIVE_ROOMS.set(count as i64),
+            Err(e) => e.log(),
+        }
+
+        tokio::time::sleep(Duration::from_secs(ACTIVE_ROOMS_INTERVAL)).await;
+    }
+}
+
+async fn active_rooms(redis: &mut RedisConnection) -> BotResult<usize> {
+    let mut rooms = HashSet::new();
+
+    for key in scan_keys("user:*:room", redis).await? {
+        let room_id: Option<String> = redis.hget(&key, "id").await?;
+        rooms.extend(room_id);
+    }
+
+    Ok(rooms.len())
+}
@@ -0,0 +1,83 @@
+use crate::analytics;
+use crate::bot::constants::RedisKeys;
+use crate::error::BotResult;
+use crate::settings::settings;
+use crate::storage::RedisConnection;
+
+use redis::AsyncCommands;
+use reqwest::Client;
+
+/// Changes of the data in Redis, in order. `schema_version` holds the number of the applied ones.
+const MIGRATIONS: [&str; 1] = ["expire user contexts kept forever"];
+
+/// Tables of the answers, every statement can be run again.
+const CLICKHOUSE_SCHEMA: &str = include_str!("../schema/clickhouse.sql");
+
+/// Applies the pending migrations and returns their descriptions.
+pub async fn migrate(redis: &mut RedisConnection) -> BotResult<Vec<&'static str>> {
+    let version: usize = redis
+        .get::<_, Option<usize>>(RedisKeys::SCHEMA_VERSION)
+        .await?
+        .unwrap_or(0);
+    let mut applied = vec![];
+
+    for (i, &description) in MIGRATIONS.iter().enumerate().skip(version) {
+        match i {
+            0 => expire_without_ttl("user:*:context", redis).await?,
+            _ => unreachable!(),
+        }
+
+        let _: () = redis.set(RedisKeys::SCHEMA_VERSION, i + 1).await?;
+        applied.push(description);
+    }
+
+    Ok(applied)
+}
+
+/// Creates the ClickHouse tables and adds the columns they lack, returns the number of statements run.
+pub async fn migrate_analytics(client: &Client, ch_url: &String) -> BotResult<usize> {
+    let statements = schema_statements(CLICKHOUSE_SCHEMA);
+
+    for statement in statements.iter() {
+        analytics::query(client, ch_url, statement.to_string(), "migrate").await?;
+    }
+
+    Ok(statements.len())
+}
+
+fn schema_statements(schema: &str) -> Vec<String> {
+    let code: Vec<&str> = schema
+        .lines()
+        .filter(|x| !x.trim_start().starts_with("--"))
+        .collect();
+
+    code.join("\n")
+        .split(';')
+        .map(|x| x.trim().to_string())
+        .filter(|x| !x.is_empty())
+        .collect()
+}
+
+/// Keys matching the pattern, scanned in batches so that Redis is not blocked.
+pub async fn scan_keys(pattern: &str, redis: &mut RedisConnection) -> BotResult<Vec<String>> {
+    let mut iter = redis.scan_match::<_, String>(pattern).await?;
+    let mut keys = vec![];
+
+    while let Some(key) = iter.next_item().await {
+        keys.push(key);
+    }
+
+    Ok(keys)
+}
+
+async fn expire_without_ttl(pattern: &str, redis: &mut RedisConnection) -> BotResult<()> {
+    for key in scan_keys(pattern, redis).await? {
+        let ttl: i64 = redis.ttl(&key).await?;
+
+        if ttl == -1 {
+            let _: () = redis.expire(&key, settings().storage.ttl).await?;
+        }
+    }
+
+    Ok(())
+}
@@ -0,0 +1,409 @@
+use crate::error::{BotError, BotResult};
+use crate::tools::read_key_env;
+
+use serde::Deserialize;
+use std::net::SocketAddr;
+use std::str::FromStr;
+use std::sync::OnceLock;
+use std::time::Duration;
+use tracing_subscriber::filter::LevelFilter;
+
+static SETTINGS: OnceLock<Settings> = OnceLock::new();
+
+/// Settings of the bot loaded once on startup.
+pub fn settings() -> &'static Settings {
+    SETTINGS.get().expect("Settings are not loaded")
+}
+
+/// Services a command can't run without besides Redis, which all of them need.
+#[derive(Clone, Copy, Debug)]
+pub struct Required {
+    pub telegram: bool,
+    pub analytics: bool,
+}
+
+/// Settings read from the TOML file in `CONFIG` (`config.toml` by default, optional)
+/// and then from the environment variables, which take precedence.
+/// See `config.example.toml` for the file and the variables of each setting.
+#[derive(Deserialize, Debug)]
+#[serde(default, deny_unknown_fields)]
+pub struct Settings {
+    pub log_level: String,
+    /// `text` or `json` with a field per value.
+    pub log_format: String,
+    /// Log the texts of messages and the names of users, they are redacted otherwise.
+    pub log_private: bool,
+    pub telegram: TelegramSettings,
+    pub storage: StorageSettings,
+    pub analytics: AnalyticsSettings,
+    pub http: HttpSettings,
+    pub limits: Limits,
+    pub features: Features,
+    pub ratings: RatingSettings,
+    pub timer: TimerSettings,
+    pub rooms: RoomSettings,
+}
+
+#[derive(Deserialize, Debug)]
+#[serde(default, deny_unknown_fields)]
+pub struct TelegramSettings {
+    pub token: String,
+    /// Signs the callback data of buttons, the token is used if it's empty.
+    pub callback_secret: String,
+    /// Seconds a long poll waits for updates.
+    pub poll_timeout: u64,
+    /// Seconds to wait before polling again after a failure.
+    pub poll_retry_delay: u64,
+}
+
+#[derive(Deserialize, Debug)]
+#[serde(default, deny_unknown_fields)]
+pub struct StorageSettings {
+    pub url: String,
+    pub pool_size: usize,
+    pub connect_timeout_ms: u64,
+    pub command_timeout_ms: u64,
+    /// Seconds rooms and user data are kept after their last use.
+    pub ttl: usize,
+}
+
+#[derive(Deserialize, Debug)]
+#[serde(default, deny_unknown_fields)]
+pub struct AnalyticsSettings {
+    /// ClickHouse HTTP interface.
+    pub url: String,
+}
+
+#[derive(Deserialize, Debug)]
+#[serde(default, deny_unknown_fields)]
+pub struct HttpSettings {
+    /// Address of `/metrics`, `/healthz` and `/readyz`, the server is off if it's empty.
+    pub address: String,
+}
+
+#[derive(Deserialize, Debug)]
+#[serde(default, deny_unknown_fields)]
+pub struct Limits {
+    /// Updates of a worker are handled one by one, workers run concurrently.
+    pub workers: usize,
+    /// Updates waiting in a worker queue before polling stops.
+    pub worker_queue: usize,
+    /// Seconds given to queued updates on shutdown.
+    pub shutdown_timeout: u64,
+}
+
+#[derive(Deserialize, Debug)]
+#[serde(default, deny_unknown_fields)]
+pub struct Features {
+    /// Offer a discussion timer when creating a room.
+    pub timers: bool,
+}
+
+/// Labels of the rating buttons from the lowest rating, the reports expect five ratings.
+#[derive(Deserialize, Debug)]
+#[serde(default, deny_unknown_fields)]
+pub struct RatingSettings {
+    pub importance: [String; 5],
+    pub evaluation: [String; 5],
+}
+
+#[derive(Deserialize, Debug)]
+#[serde(default, deny_unknown_fields)]
+pub struct TimerSettings {
+    /// Discussion times in minutes offered besides no timer.
+    pub options: Vec<u64>,
+    /// Seconds between edits of the countdown messages.
+    pub tick: u64,
+    /// Seconds between checks for countdowns to update.
+    pub poll_interval: u64,
+}
+
+#[derive(Deserialize, Debug)]
+#[serde(default, deny_unknown_fields)]
+pub struct RoomSettings {
+    /// Questions in a room with the quick order, fewer if the pack is shorter.
+    pub quick_length: u16,
+}
+
+impl Default for Settings {
+    fn default() -> Settings {
+        Settings {
+            log_level: "info".to_string(),
+            log_format: "text".to_string(),
+            log_private: false,
+            telegram: TelegramSettings::default(),
+            storage: StorageSettings::default(),
+            analytics: AnalyticsSettings::default(),
+            http: HttpSettings::default(),
+            limits: Limits::default(),
+            features: Features::default(),
+            ratings: RatingSettings::default(),
+            timer: TimerSettings::default(),
+            rooms: RoomSettings::default(),
+        }
+    }
+}
+
+impl Default for TelegramSettings {
+    fn default() -> TelegramSettings {
+        TelegramSettings {
+            token: String::new(),
+            callback_secret: String::new(),
+            poll_timeout: 60,
+            poll_retry_delay: 5,
+        }
+    }
+}
+
+impl Default for StorageSettings {
+    fn default() -> StorageSettings {
+        StorageSettings {
+            url: "redis://127.0.0.1/".to_string(),
+            pool_size: 4,
+            connect_timeout_ms: 5000,
+            command_timeout_ms: 5000,
+            ttl: 2592000,
+        }
+    }
+}
+
+impl Default for AnalyticsSettings {
+    fn default() -> AnalyticsSettings {
+        AnalyticsSettings {
+            url: "http://127.0.0.1:8123/".to_string(),
+        }
+    }
+}
+
+impl Default for HttpSettings {
+    fn default() -> HttpSettings {
+        HttpSettings {
+            address: "0.0.0.0:9090".to_string(),
+        }
+    }
+}
+
+impl Default for Limits {
+    fn default() -> Limits {
+        Limits {
+            workers: 8,
+            worker_queue: 64,
+            // container runtimes usually kill the process 10 seconds after SIGTERM
+            shutdown_timeout: 8,
+        }
+    }
+}
+
+impl Default for Features {
+    fn default() -> Features {
+        Features { timers: true }
+    }
+}
+
+impl Default for RatingSettings {
+    fn default() -> RatingSettings {
+        let labels = |labels: [&str; 5]| labels.map(str::to_string);
+
+        RatingSettings {
+            importance: labels([" 0️", "✔️", "❗", "‼️", "️🔥"]),
+            evaluation: labels(["😡", "🙁", "😐", "😊", "️😀"]),
+        }
+    }
+}
+
+impl Default for TimerSettings {
+    fn default() -> TimerSettings {
+        TimerSettings {
+            options: vec![3, 5, 10],
+            tick: 15,
+            poll_interval: 5,
+        }
+    }
+}
+
+impl Default for RoomSettings {
+    fn default() -> RoomSettings {
+        RoomSettings { quick_length: 10 }
+    }
+}
+
+impl StorageSettings {
+    pub fn connect_timeout(&self) -> Duration {
+        Duration::from_millis(self.connect_timeout_ms)
+    }
+
+    pub fn command_timeout(&self) -> Duration {
+        Duration::from_millis(self.command_timeout_ms)
+    }
+}
+
+impl HttpSettings {
+    pub fn address(&self) -> Option<SocketAddr> {
+        self.address.parse().ok()
+    }
+}
+
+impl Settings {
+    const DEFAULT_PATH: &'static str = "config.toml";
+
+    /// Reads and validates the settings and makes them available through `settings()`.
+    pub fn init(required: Required) -> BotResult<&'static Settings> {
+        let settings = Settings::load(required)?;
+        Ok(SETTINGS.get_or_init(|| settings))
+    }
+
+    fn load(required: Required) -> BotResult<Settings> {
+        let path = read_key_env("CONFIG");
+        let mut settings =
+            match std::fs::read_to_string(path.as_deref().unwrap_or(Settings::DEFAULT_PATH)) {
+                Ok(file) => toml::from_str(&file)
+                    .map_err(|e| BotError::Fatal(format!("Invalid settings file: {}", e)))?,
+                // the file is optional unless it's set explicitly
+                Err(e) if path.is_none() && e.kind() == std::io::ErrorKind::NotFound => {
+                    Settings::default()
+                }
+                Err(e) => return Err(BotError::Fatal(format!("Can't read settings: {}", e))),
+            };
+
+        let mut errors = settings.apply_env();
+        errors.extend(settings.validate(required));
+
+        if errors.is_empty() {
+            Ok(settings)
+        } else {
+            Err(BotError::Fatal(format!(
+                "Invalid settings:\n{}",
+                errors.join("\n")
+            )))
+        }
+    }
+
+    fn apply_env(&mut self) -> Vec<String> {
+        let mut errors = vec![];
+
+        override_env("LOG_LEVEL", &mut self.log_level, &mut errors);
+        override_env("LOG_FORMAT", &mut self.log_format, &mut errors);
+        override_env("LOG_PRIVATE", &mut self.log_private, &mut errors);
+        override_env("TG_TOKEN", &mut self.telegram.token, &mut errors);
+        override_env(
+            "CALLBACK_SECRET",
+            &mut self.telegram.callback_secret,
+            &mut errors,
+        );
+        override_env("POLL_TIMEOUT", &mut self.telegram.poll_timeout, &mut errors);
+        override_env(
+            "POLL_RETRY_DELAY",
+            &mut self.telegram.poll_retry_delay,
+            &mut errors,
+        );
+        override_env("REDIS", &mut self.storage.url, &mut errors);
+        override_env("REDIS_POOL_SIZE", &mut self.storage.pool_size, &mut errors);
+        override_env(
+            "REDIS_CONNECT_TIMEOUT_MS",
+            &mut self.storage.connect_timeout_ms,
+            &mut errors,
+        );
+        override_env(
+            "REDIS_COMMAND_TIMEOUT_MS",
+            &mut self.storage.command_timeout_ms,
+            &mut errors,
+        );
+        override_env("TTL", &mut self.storage.ttl, &mut errors);
+        override_env("CH_URL", &mut self.analytics.url, &mut errors);
+        override_env("HTTP_ADDRESS", &mut self.http.address, &mut errors);
+        override_env("WORKERS", &mut self.limits.workers, &mut errors);
+        override_env("WORKER_QUEUE", &mut self.limits.worker_queue, &mut errors);
+        override_env(
+            "SHUTDOWN_TIMEOUT",
+            &mut self.limits.shutdown_timeout,
+            &mut errors,
+        );
+        override_env("TIMERS", &mut self.features.timers, &mut errors);
+
+        errors
+    }
+
+    fn validate(&self, required: Required) -> Vec<String> {
+        let mut errors = vec![];
+        let mut check = |valid: bool, error: &str| {
+            if !valid {
+                errors.push(error.to_string());
+            }
+        };
+
+        check(
+            self.log_level().is_some(),
+            "log_level must be one of off, error, warn, info, debug, trace",
+        );
+        check(
+            ["text", "json"].contains(&self.log_format.as_str()),
+            "log_format must be text or json",
+        );
+        check(
+            !required.telegram || !self.telegram.token.is_empty(),
+            "telegram.token (TG_TOKEN) is required",
+        );
+        check(
+            self.telegram.poll_timeout <= 300,
+            "telegram.poll_timeout must be at most 300 seconds",
+        );
+        check(
+            redis::parse_redis_url(&self.storage.url).is_ok(),
+            "storage.url (REDIS) must be a redis:// URL",
+        );
+        check(
+            self.storage.pool_size > 0,
+            "storage.pool_size must be positive",
+        );
+        check(self.storage.ttl > 0, "storage.ttl must be positive");
+        check(
+            !required.analytics || reqwest::Url::parse(&self.analytics.url).is_ok(),
+            "analytics.url (CH_URL) must be a URL",
+        );
+        check(
+            self.http.address.is_empty() || self.http.address().is_some(),
+            "http.address (HTTP_ADDRESS) must be an address such as 0.0.0.0:9090",
+        );
+        check(self.limits.workers > 0, "limits.workers must be positive");
+        check(
+            self.limits.worker_queue > 0,
+            "limits.worker_queue must be positive",
+        );
+        check(
+            self.timer.options.iter().all(|&minutes| minutes > 0) && self.timer.options.len() <= 8,
+            "timer.options must be at most 8 positive numbers of minutes",
+        );
+        check(self.timer.tick > 0, "timer.tick must be positive");
+        check(
+            self.timer.poll_interval > 0,
+            "timer.poll_interval must be positive",
+        );
+        check(
+            self.rooms.quick_length > 0,
+            "rooms.quick_length must be positive",
+        );
+
+        errors
+    }
+
+    pub fn log_level(&self) -> Option<LevelFilter> {
+        LevelFilter::from_str(&self.log_level).ok()
+    }
+
+    pub fn callback_secret(&self) -> &str {
+        if self.telegram.callback_secret.is_empty() {
+            &self.telegram.token
+        } else {
+            &self.telegram.callback_secret
+        }
+    }
+}
+
+fn override_env<T: FromStr>(key: &str, value: &mut T, errors: &mut Vec<String>) {
+    if let Some(env_value) = read_key_env(key) {
+        match env_value.parse() {
+            Ok(parsed) => *value = parsed,
+            Err(_) => errors.push(format!("{} has an invalid value: {}", key, env_value)),
+        }
+    }
+}
@@ -0,0 +1,154 @@
+use crate::metrics::REDIS_DURATION;
+use crate::settings::StorageSettings;
+
+use redis::aio::{ConnectionLike, MultiplexedConnection};
+use redis::{Cmd, Pipeline, RedisError, RedisFuture, RedisResult, Value};
+use std::io;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use tokio::sync::Mutex;
+
+// how long the bot waits for Redis on startup before giving up
+const STARTUP_RETRIES: u32 = 10;
+const STARTUP_MAX_BACKOFF: u64 = 30;
+
+/// Multiplexed connections to Redis shared by all tasks.
+/// A connection which fails with an IO error or a timeout is dropped and opened again on the next command,
+/// so a Redis outage fails the updates handled meanwhile instead of stopping the bot.
+pub struct RedisPool {
+    client: redis::Client,
+    slots: Vec<Mutex<Option<MultiplexedConnection>>>,
+    next: AtomicUsize,
+    connect_timeout: Duration,
+    command_timeout: Duration,
+}
+
+impl RedisPool {
+    /// Opens the pool, retrying with a growing delay while Redis is unavailable.
+    pub async fn connect(settings: &StorageSettings) -> RedisResult<Arc<RedisPool>> {
+        let slots = (0..settings.pool_size.max(1))
+            .map(|_| Mutex::new(None))
+            .collect();
+        let pool = Arc::new(RedisPool {
+            client: redis::Client::open(settings.url.as_str())?,
+            slots,
+            next: AtomicUsize::new(0),
+            connect_timeout: settings.connect_timeout(),
+            command_timeout: settings.command_timeout(),
+        });
+
+        let mut backoff = 1;
+        for attempt in 1..=STARTUP_RETRIES {
+            match pool.slot_connection(0).await {
+                Ok(_) => return Ok(pool),
+                Err(e) if attempt == STARTUP_RETRIES => return Err(e),
+                Err(e) => {
+                    tracing::warn!(error = ?e, "Redis is unavailable, retrying in {}s", backoff);
+                    tokio::time::sleep(Duration::from_secs(backoff)).await;
+                    backoff = (backoff * 2).min(STARTUP_MAX_BACKOFF);
+                }
+            }
+        }
+
+        Ok(pool)
+    }
+
+    /// Connection bound to one of the pool slots, connections are spread over the slots in turn.
+    pub fn connection(self: &Arc<RedisPool>) -> RedisConnection {
+        let slot = self.next.fetch_add(1, Ordering::Relaxed) % self.slots.len();
+
+        RedisConnection {
+            pool: self.clone(),
+            slot,
+        }
+    }
+
+    async fn slot_connection(&self, slot: usize) -> RedisResult<MultiplexedConnection> {
+        let mut connection = self.slots[slot].lock().await;
+
+        if let Some(connection) = connection.as_ref() {
+            return Ok(connection.clone());
+        }
+
+        let connect = self.client.get_multiplexed_tokio_connection();
+        let new_connection = tokio::time::timeout(self.connect_timeout, connect)
+            .await
+            .map_err(|_| timeout_error("Redis connection timed out"))??;
+        *connection = Some(new_connection.clone());
+
+        Ok(new_connection)
+    }
+
+    async fn reset(&self, slot: usize, error: &RedisError) {
+        if error.is_io_error() || error.is_connection_dropped() || error.is_timeout() {
+            tracing::warn!(?error, "Dropping Redis connection");
+            *self.slots[slot].lock().await = None;
+        }
+    }
+}
+
+fn observe<T>(started: Instant, result: &RedisResult<T>) {
+    let outcome = if result.is_ok() { "ok" } else { "error" };
+    REDIS_DURATION
+        .with_label_values(&[outcome])
+        .observe(started.elapsed().as_secs_f64());
+}
+
+fn timeout_error(message: &str) -> RedisError {
+    io::Error::new(io::ErrorKind::TimedOut, message.to_string()).into()
+}
+
+#[derive(Clone)]
+pub struct RedisConnection {
+    pool: Arc<RedisPool>,
+    slot: usize,
+}
+
+impl ConnectionLike for RedisConnection {
+    fn req_packed_command<'a>(&'a mut self, cmd: &'a Cmd) -> RedisFuture<'a, Value> {
+        Box::pin(async move {
+            let mut connection = self.pool.slot_connection(self.slot).await?;
+            let timeout = self.pool.command_timeout;
+            let started = Instant::now();
+            let result = tokio::time::timeout(timeout, connection.req_packed_command(cmd))
+                .await
+                .unwrap_or_else(|_| Err(timeout_error("Redis command timed out")));
+            observe(started, &result);
+
+            if let Err(e) = &result {
+                self.pool.reset(self.slot, e).await;
+            }
+
+            result
+        })
+    }
+
+    fn req_packed_commands<'a>(
+        &'a mut self,
+        cmd: &'a Pipeline,
+        offset: usize,
+        count: usize,
+    ) -> RedisFuture<'a, Vec<Value>> {
+        Box::pin(async move {
+            let mut connection = self.pool.slot_connection(self.slot).await?;
+            let timeout = self.pool.command_timeout;
+            let commands = connection.req_packed_commands(cmd, offset, count);
+            let started = Instant::now();
+            let result = tokio::time::timeout(timeout, commands)
+                .await
+                .unwrap_or_else(|_| Err(timeout_error("Redis command timed out")));
+            observe(started, &result);
+
+            if let Err(e) = &result {
+                self.pool.reset(self.slot, e).await;
+            }
+
+            result
+        })
+    }
+
+    fn get_db(&self) -> i64 {
+        0
+    }
+}
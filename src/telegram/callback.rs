@@ -0,0 +1,477 @@
+use crate::bot::room::Room;
+use crate::settings::settings;
+use crate::storage::RedisConnection;
+use crate::telegram::structures::{CallbackData, NavigationData};
+use crate::ternary;
+
+use hmac::{Hmac, Mac};
+use lazy_static::lazy_static;
+use serde::Deserialize;
+use sha2::Sha256;
+use std::convert::TryInto;
+
+lazy_static! {
+    // the bot token is a server secret too, so a separate one is optional
+    static ref SECRET: Vec<u8> = settings().callback_secret().as_bytes().to_vec();
+}
+
+/// Callback data of a button, encoded as url-safe base64 of
+/// `[version, action, value, room handle (u32) and question (u16) for rating buttons, signature]`.
+/// Pack buttons carry the pack name in place of the value.
+/// The signature is a truncated HMAC-SHA256 of the preceding bytes.
+/// JSON and unsigned buttons sent by earlier versions are still decoded in the shapes they were sent,
+/// but their ratings carry no question and pack buttons, which held a position, are rejected.
+#[derive(Debug)]
+pub enum CallbackPayload {
+    Rating(CallbackData),
+    Navigation(NavigationData),
+    Invalid,
+}
+
+impl CallbackPayload {
+    const VERSION: u8 = 2;
+    const VERSION_UNSIGNED: u8 = 1;
+    const SIGNATURE_LEN: usize = 8;
+    // actions below are rating types, navigation buttons use NAVIGATION + nav
+    const NAVIGATION: u8 = 0x40;
+    // the longest name which keeps the encoded data within the 64 bytes Telegram allows
+    const PACK_NAME_LEN: usize = 38;
+    // navigation sent in JSON or unsigned
+    const LEGACY_NAVIGATION: [u8; 6] = [
+        NavigationData::CREATE,
+        NavigationData::JOIN,
+        NavigationData::READY,
+        NavigationData::PACK_LANGUAGE,
+        NavigationData::ORDER,
+        NavigationData::TIMER,
+    ];
+
+    fn signature(key: &[u8], bytes: &[u8]) -> Vec<u8> {
+        let mut mac = Hmac::<Sha256>::new_from_slice(key).expect("HMAC takes keys of any size");
+        mac.update(bytes);
+
+        mac.finalize().into_bytes()[..CallbackPayload::SIGNATURE_LEN].to_vec()
+    }
+
+    fn encode_bytes(key: &[u8], action: u8, data: &[u8]) -> String {
+        let mut bytes = vec![CallbackPayload::VERSION, action];
+        bytes.extend(data);
+        bytes.extend(CallbackPayload::signature(key, &bytes));
+        base64::encode_config(bytes, base64::URL_SAFE_NO_PAD)
+    }
+
+    pub(crate) async fn encode_rating(
+        data: &CallbackData,
+        question: u16,
+        redis: &mut RedisConnection,
+    ) -> redis::RedisResult<String> {
+        let handle = Room::handle(&data.room_id, redis).await?;
+        Ok(CallbackPayload::encode_rating_bytes(
+            &SECRET, data, handle, question,
+        ))
+    }
+
+    fn encode_rating_bytes(key: &[u8], data: &CallbackData, handle: u32, question: u16) -> String {
+        let mut bytes = vec![data.idx];
+        bytes.extend(&handle.to_be_bytes());
+        bytes.extend(&question.to_be_bytes());
+
+        CallbackPayload::encode_bytes(key, data.typ, &bytes)
+    }
+
+    pub(crate) fn encode_navigation(data: &NavigationData) -> String {
+        CallbackPayload::encode_navigation_bytes(&SECRET, data)
+    }
+
+    fn encode_navigation_bytes(key: &[u8], data: &NavigationData) -> String {
+        let action = CallbackPayload::NAVIGATION + data.nav;
+        let bytes = match &data.pack {
+            Some(pack) => CallbackPayload::pack_prefix(pack),
+            None => std::slice::from_ref(&data.value),
+        };
+
+        CallbackPayload::encode_bytes(key, action, bytes)
+    }
+
+    fn pack_prefix(pack: &str) -> &[u8] {
+        let mut len = pack.len().min(CallbackPayload::PACK_NAME_LEN);
+        while !pack.is_char_boundary(len) {
+            len -= 1;
+        }
+
+        &pack.as_bytes()[..len]
+    }
+
+    pub(crate) async fn decode(
+        data: &str,
+        redis: &mut RedisConnection,
+    ) -> redis::RedisResult<CallbackPayload> {
+        let payload = match CallbackPayload::parse(&SECRET, data) {
+            Body::Payload(payload) => payload,
+            Body::Rating {
+                typ,
+                idx,
+                handle,
+                question,
+            } => match Room::by_handle(handle, redis).await? {
+                Some(room_id) => CallbackPayload::Rating(CallbackData {
+                    idx,
+                    typ,
+                    room_id,
+                    question,
+                }),
+                None => CallbackPayload::Invalid,
+            },
+        };
+
+        Ok(payload)
+    }
+
+    fn parse(key: &[u8], data: &str) -> Body {
+        if data.starts_with('{') {
+            return Body::Payload(CallbackPayload::decode_json(data));
+        }
+
+        let bytes = match base64::decode_config(data, base64::URL_SAFE_NO_PAD) {
+            Ok(bytes) => bytes,
+            Err(_) => return Body::Payload(CallbackPayload::Invalid),
+        };
+
+        match bytes.split_first() {
+            Some((&CallbackPayload::VERSION, _))
+                if bytes.len() > CallbackPayload::SIGNATURE_LEN =>
+            {
+                let (body, signature) =
+                    bytes.split_at(bytes.len() - CallbackPayload::SIGNATURE_LEN);
+
+                if CallbackPayload::signature(key, body) != signature {
+                    return Body::Payload(CallbackPayload::Invalid);
+                }
+
+                CallbackPayload::parse_body(&body[1..], true)
+            }
+            Some((&CallbackPayload::VERSION_UNSIGNED, body)) => {
+                CallbackPayload::parse_body(body, false)
+            }
+            _ => Body::Payload(CallbackPayload::Invalid),
+        }
+    }
+
+    fn parse_body(body: &[u8], signed: bool) -> Body {
+        let payload = match body {
+            [action, name @ ..]
+                if signed && *action == CallbackPayload::NAVIGATION + NavigationData::PACK =>
+            {
+                match std::str::from_utf8(name) {
+                    Ok(name) => CallbackPayload::Navigation(NavigationData {
+                        nav: NavigationData::PACK,
+                        value: 0,
+                        pack: Some(name.to_string()),
+                    }),
+                    Err(_) => CallbackPayload::Invalid,
+                }
+            }
+            [action, value] if *action > CallbackPayload::NAVIGATION => {
+                let nav = action - CallbackPayload::NAVIGATION;
+                ternary!(
+                    signed,
+                    CallbackPayload::Navigation(NavigationData {
+                        nav,
+                        value: *value,
+                        pack: None,
+                    }),
+                    CallbackPayload::legacy_navigation(nav, *value)
+                )
+            }
+            [typ, idx, rest @ ..] if rest.len() == ternary!(signed, 6, 4) => {
+                return Body::Rating {
+                    typ: *typ,
+                    idx: *idx,
+                    handle: u32::from_be_bytes(rest[..4].try_into().unwrap_or_default()),
+                    question: ternary!(
+                        signed,
+                        Some(u16::from_be_bytes(rest[4..].try_into().unwrap_or_default())),
+                        None
+                    ),
+                };
+            }
+            _ => CallbackPayload::Invalid,
+        };
+
+        Body::Payload(payload)
+    }
+
+    fn decode_json(data: &str) -> CallbackPayload {
+        if let Ok(navigation) = serde_json::from_str::<LegacyNavigation>(data) {
+            return CallbackPayload::legacy_navigation(navigation.nav, navigation.value);
+        }
+
+        serde_json::from_str::<LegacyRating>(data)
+            .map(|x| {
+                CallbackPayload::Rating(CallbackData {
+                    idx: x.idx,
+                    typ: x.typ,
+                    room_id: x.room_id,
+                    question: None,
+                })
+            })
+            .unwrap_or(CallbackPayload::Invalid)
+    }
+
+    fn legacy_navigation(nav: u8, value: u8) -> CallbackPayload {
+        ternary!(
+            CallbackPayload::LEGACY_NAVIGATION.contains(&nav),
+            CallbackPayload::Navigation(NavigationData {
+                nav,
+                value,
+                pack: None,
+            }),
+            CallbackPayload::Invalid
+        )
+    }
+}
+
+/// Rating button data in JSON as sent before the encoding was introduced.
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct LegacyRating {
+    idx: u8,
+    typ: u8,
+    room_id: String,
+}
+
+/// Navigation button data in JSON as sent before the encoding was introduced.
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct LegacyNavigation {
+    nav: u8,
+    value: u8,
+}
+
+/// Decoded callback data, rating buttons still need their room looked up by the handle.
+enum Body {
+    Payload(CallbackPayload),
+    Rating {
+        typ: u8,
+        idx: u8,
+        handle: u32,
+        question: Option<u16>,
+    },
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const KEY: &[u8] = b"test secret";
+
+    fn rating(typ: u8, idx: u8) -> CallbackData {
+        CallbackData {
+            idx,
+            typ,
+            room_id: String::new(),
+            question: None,
+        }
+    }
+
+    fn navigation(nav: u8, value: u8, pack: Option<&str>) -> NavigationData {
+        NavigationData {
+            nav,
+            value,
+            pack: pack.map(str::to_string),
+        }
+    }
+
+    fn encode_raw(bytes: &[u8]) -> String {
+        base64::encode_config(bytes, base64::URL_SAFE_NO_PAD)
+    }
+
+    fn is_invalid(data: &str) -> bool {
+        matches!(
+            CallbackPayload::parse(KEY, data),
+            Body::Payload(CallbackPayload::Invalid)
+        )
+    }
+
+    #[test]
+    fn rating_round_trip() {
+        let data = CallbackPayload::encode_rating_bytes(KEY, &rating(2, 4), 70_000, 513);
+
+        match CallbackPayload::parse(KEY, &data) {
+            Body::Rating {
+                typ,
+                idx,
+                handle,
+                question,
+            } => {
+                assert_eq!((typ, idx, handle, question), (2, 4, 70_000, Some(513)));
+            }
+            _ => panic!("rating is not decoded"),
+        }
+    }
+
+    #[test]
+    fn navigation_round_trip() {
+        let data = CallbackPayload::encode_navigation_bytes(
+            KEY,
+            &navigation(NavigationData::TIMER, 3, None),
+        );
+
+        match CallbackPayload::parse(KEY, &data) {
+            Body::Payload(CallbackPayload::Navigation(x)) => {
+                assert_eq!((x.nav, x.value, x.pack), (NavigationData::TIMER, 3, None));
+            }
+            _ => panic!("navigation is not decoded"),
+        }
+    }
+
+    #[test]
+    fn pack_round_trip() {
+        let pack = Some("Первое свидание");
+        let data = CallbackPayload::encode_navigation_bytes(
+            KEY,
+            &navigation(NavigationData::PACK, 0, pack),
+        );
+
+        match CallbackPayload::parse(KEY, &data) {
+            Body::Payload(CallbackPayload::Navigation(x)) => {
+                assert_eq!(x.nav, NavigationData::PACK);
+                assert_eq!(x.pack.as_deref(), pack);
+            }
+            _ => panic!("pack is not decoded"),
+        }
+    }
+
+    #[test]
+    fn tampered_signature_is_rejected() {
+        let data = CallbackPayload::encode_navigation_bytes(
+            KEY,
+            &navigation(NavigationData::JOIN, 0, None),
+        );
+        assert!(matches!(
+            CallbackPayload::parse(b"other secret", &data),
+            Body::Payload(CallbackPayload::Invalid)
+        ));
+
+        let mut bytes = base64::decode_config(&data, base64::URL_SAFE_NO_PAD).unwrap();
+
+        let last = bytes.len() - 1;
+        bytes[last] ^= 1;
+        assert!(is_invalid(&encode_raw(&bytes)));
+
+        bytes[last] ^= 1;
+        bytes[2] = 1;
+        assert!(is_invalid(&encode_raw(&bytes)));
+    }
+
+    #[test]
+    fn truncated_signature_is_rejected() {
+        let data = CallbackPayload::encode_rating_bytes(KEY, &rating(1, 0), 1, 0);
+        let bytes = base64::decode_config(&data, base64::URL_SAFE_NO_PAD).unwrap();
+
+        for len in 1..bytes.len() {
+            assert!(is_invalid(&encode_raw(&bytes[..len])), "length {}", len);
+        }
+        assert!(is_invalid(&data[..data.len() - 1]));
+    }
+
+    #[test]
+    fn data_fits_into_64_bytes() {
+        let data = CallbackPayload::encode_rating_bytes(
+            KEY,
+            &rating(u8::MAX, u8::MAX),
+            u32::MAX,
+            u16::MAX,
+        );
+        assert!(data.len() <= 64);
+
+        for pack in &[
+            "a".repeat(100),
+            "я".repeat(100),
+            format!("a{}", "я".repeat(100)),
+        ] {
+            let data = CallbackPayload::encode_navigation_bytes(
+                KEY,
+                &navigation(NavigationData::PACK, 0, Some(pack)),
+            );
+            assert!(data.len() <= 64, "{} bytes for {}", data.len(), pack);
+
+            match CallbackPayload::parse(KEY, &data) {
+                Body::Payload(CallbackPayload::Navigation(x)) => {
+                    assert!(pack.starts_with(x.pack.unwrap().as_str()));
+                }
+                _ => panic!("long pack is not decoded"),
+            }
+        }
+    }
+
+    #[test]
+    fn legacy_json_rating() {
+        match CallbackPayload::parse(KEY, r#"{"idx":3,"typ":1,"room_id":"abc"}"#) {
+            Body::Payload(CallbackPayload::Rating(x)) => {
+                assert_eq!(
+                    (x.idx, x.typ, x.room_id.as_str(), x.question),
+                    (3, 1, "abc", None)
+                );
+            }
+            _ => panic!("legacy rating is not decoded"),
+        }
+        assert!(is_invalid(r#"{"idx":3}"#));
+        assert!(is_invalid(
+            r#"{"idx":3,"typ":1,"room_id":"abc","question":0}"#
+        ));
+    }
+
+    #[test]
+    fn legacy_json_navigation() {
+        match CallbackPayload::parse(KEY, r#"{"nav":6,"value":2}"#) {
+            Body::Payload(CallbackPayload::Navigation(x)) => {
+                assert_eq!((x.nav, x.value, x.pack), (NavigationData::ORDER, 2, None));
+            }
+            _ => panic!("legacy navigation is not decoded"),
+        }
+        assert!(is_invalid(r#"{"nav":4,"value":0}"#));
+        assert!(is_invalid(r#"{"nav":4,"value":0,"pack":"abc"}"#));
+    }
+
+    #[test]
+    fn legacy_unsigned_buttons() {
+        let navigation = encode_raw(&[
+            CallbackPayload::VERSION_UNSIGNED,
+            CallbackPayload::NAVIGATION + NavigationData::READY,
+            0,
+        ]);
+        match CallbackPayload::parse(KEY, &navigation) {
+            Body::Payload(CallbackPayload::Navigation(x)) => {
+                assert_eq!((x.nav, x.value), (NavigationData::READY, 0));
+            }
+            _ => panic!("unsigned navigation is not decoded"),
+        }
+
+        let rating = encode_raw(&[CallbackPayload::VERSION_UNSIGNED, 2, 5, 0, 0, 1, 0]);
+        match CallbackPayload::parse(KEY, &rating) {
+            Body::Rating {
+                typ,
+                idx,
+                handle,
+                question,
+            } => {
+                assert_eq!((typ, idx, handle, question), (2, 5, 256, None));
+            }
+            _ => panic!("unsigned rating is not decoded"),
+        }
+
+        assert!(is_invalid(&encode_raw(&[
+            CallbackPayload::VERSION_UNSIGNED,
+            2,
+            5,
+            0
+        ])));
+        assert!(is_invalid(&encode_raw(&[
+            CallbackPayload::VERSION_UNSIGNED,
+            CallbackPayload::NAVIGATION + NavigationData::PACK,
+            0,
+        ])));
+        assert!(is_invalid(&encode_raw(&[3, 2, 5])));
+    }
+}
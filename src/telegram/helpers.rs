@@ -1,28 +1,92 @@
+use crate::bot::locale::Locale;
 use crate::telegram::structures::CallbackData;
 
 pub(crate) fn create_tg_url(bot_token: &str, method: &str) -> String {
     format!("https://api.telegram.org/bot{}/{}", bot_token, method)
 }
 
+/// Url of another method of the bot `url` was created for.
+pub(crate) fn replace_tg_method(url: &str, method: &str) -> String {
+    match url.rfind('/') {
+        Some(i) => format!("{}/{}", &url[..i], method),
+        None => method.to_string(),
+    }
+}
+
 #[derive(Debug)]
 pub enum UpdateType {
     Start,
     Help,
     JoinExisting,
     Create,
+    CreateGuess,
+    CreateSolo,
+    CreateAsync,
+    Compare,
+    Language,
     Callback(i64, i32, CallbackData, String),
-    NewRoom,
+    InvalidCallback,
+    SelectPack(Option<String>),
+    SelectPackLanguage(Option<Locale>),
+    SelectOrder(Option<&'static str>),
+    NewRoom(Option<u64>),
     InsertId,
     WaitingForOther,
+    Note,
     WaitingForResults,
+    ContinueAlone,
+    LeaveRoom,
     UnknownCommand,
     Other,
     Error,
 }
 
+impl UpdateType {
+    /// Label of the update type in the metrics.
+    pub(crate) fn name(&self) -> &'static str {
+        match self {
+            UpdateType::Start => "start",
+            UpdateType::Help => "help",
+            UpdateType::JoinExisting => "join_existing",
+            UpdateType::Create => "create",
+            UpdateType::CreateGuess => "create_guess",
+            UpdateType::CreateSolo => "create_solo",
+            UpdateType::CreateAsync => "create_async",
+            UpdateType::Compare => "compare",
+            UpdateType::Language => "language",
+            UpdateType::Callback(..) => "callback",
+            UpdateType::InvalidCallback => "invalid_callback",
+            UpdateType::SelectPack(_) => "select_pack",
+            UpdateType::SelectPackLanguage(_) => "select_pack_language",
+            UpdateType::SelectOrder(_) => "select_order",
+            UpdateType::NewRoom(_) => "new_room",
+            UpdateType::InsertId => "insert_id",
+            UpdateType::WaitingForOther => "waiting_for_other",
+            UpdateType::Note => "note",
+            UpdateType::WaitingForResults => "waiting_for_results",
+            UpdateType::ContinueAlone => "continue_alone",
+            UpdateType::LeaveRoom => "leave_room",
+            UpdateType::UnknownCommand => "unknown_command",
+            UpdateType::Other => "other",
+            UpdateType::Error => "error",
+        }
+    }
+}
+
 #[derive(Debug)]
 pub(crate) enum CallbackMessageType {
     Importance,
     Evaluation,
+    Guess,
     Error,
 }
+
+#[derive(Debug, PartialEq)]
+pub(crate) enum RatingUpdate {
+    Set,
+    // the role has just set all its ratings for the question
+    Completed,
+    // guesses can't be changed once made
+    Locked,
+    Ignored,
+}
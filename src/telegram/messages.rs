@@ -1,179 +1,503 @@
 use crate::bot::constants::*;
-use crate::bot::report::ReportData;
+use crate::bot::locale::Locale;
+use crate::bot::order::QuestionOrder;
+use crate::bot::pack::Pack;
+use crate::bot::report::{GuessReportData, ReportData};
 use crate::bot::room::*;
+use crate::bot::solo::SoloRun;
+use crate::bot::timer::QuestionTimer;
+use crate::error::BotResult;
+use crate::metrics::RoomEvent;
+use crate::settings::settings;
+use crate::storage::RedisConnection;
+use crate::telegram::helpers::replace_tg_method;
+use crate::telegram::sender::{self, SendError};
 use crate::telegram::structures::*;
 use crate::ternary;
 
-use redis::Commands;
+use redis::AsyncCommands;
 use reqwest::Client;
 use serde::Serialize;
+use std::collections::HashMap;
+
+/// Messages with buttons of the question the user is on, stripped once the user leaves it.
+pub(crate) struct QuestionKeyboards;
+impl QuestionKeyboards {
+    fn key_str(user_id: i32) -> String {
+        format!("user:{}:keyboards", user_id)
+    }
+
+    pub(crate) async fn remember(
+        user_id: i32,
+        message_id: i32,
+        redis: &mut RedisConnection,
+    ) -> redis::RedisResult<()> {
+        if message_id == 0 {
+            return Ok(());
+        }
+
+        let key = QuestionKeyboards::key_str(user_id);
+        let _: () = redis.rpush(&key, message_id).await?;
+        redis.expire(&key, settings().storage.ttl).await
+    }
+
+    /// Removes the buttons of the previous question, so that they can't be pressed anymore.
+    pub(crate) async fn strip(
+        user_id: i32,
+        redis: &mut RedisConnection,
+        client: &Client,
+        url: &str,
+    ) -> BotResult<()> {
+        let key = QuestionKeyboards::key_str(user_id);
+        let message_ids: Vec<i32> = redis.lrange(&key, 0, -1).await?;
+        let _: () = redis.del(&key).await?;
+        let edit_url = replace_tg_method(url, TgMethods::EDIT_MESSAGE_REPLY_MARKUP);
+
+        for message_id in message_ids {
+            EditedReplyInlineMarkup::remove(user_id as i64, message_id)
+                .edit_with_url(&edit_url, client)
+                .await?;
+        }
+
+        Ok(())
+    }
+}
 
 #[derive(Debug)]
 pub(crate) struct QuestionMessage {
-    header: String,
-    message: String,
+    idx: u16,
+    total: u16,
+    pack: String,
+    position: u16,
+    language: Locale,
 }
 
 impl QuestionMessage {
-    pub fn create_text(&self) -> String {
-        format!("{}{}", self.header, self.message)
+    /// Text of the question in the user's locale if the pack is translated to it,
+    /// otherwise in the language of the room.
+    pub async fn create_text(
+        &self,
+        locale: Locale,
+        redis: &mut RedisConnection,
+    ) -> Result<String, redis::RedisError> {
+        let message = Pack::question(&self.pack, self.position, &[locale, self.language], redis)
+            .await?
+            .unwrap_or_default();
+        let header = locale.format(
+            Messages::QUESTION_HEADER,
+            &[
+                ("number", (self.idx + 1).to_string()),
+                ("total", self.total.to_string()),
+            ],
+        );
+
+        Ok(format!("{}{}", header, message))
     }
 
-    fn get(
-        pack: &String,
+    pub(crate) async fn get(
+        room_id: &String,
         idx: u16,
-        redis: &mut redis::Connection,
+        redis: &mut RedisConnection,
     ) -> Result<Option<QuestionMessage>, redis::RedisError> {
-        let pack_message: Option<String> = redis.lindex(format!("pack:{}", pack), idx as isize)?;
+        let room_key = Room::key(room_id, redis).await?;
+        let room: HashMap<String, String> = redis.hgetall(&room_key).await?;
+        let pack = room.get("pack").cloned().unwrap_or_default();
+        let pack_len = Pack::len(&pack, redis).await?;
 
-        if pack_message.is_some() {
-            let pack_len: u16 = redis.llen(format!("pack:{}", pack))?;
-            let header = format!("<b>📒Вопрос {} из {}:</b>\n", idx + 1, pack_len);
-            let message = pack_message.unwrap();
+        match QuestionOrder::position(room_id, idx, redis).await? {
+            Some(position) if position < pack_len => {
+                let language = match room.get("language").and_then(|x| Locale::from_code(x)) {
+                    Some(language) => language,
+                    None => Pack::language(&pack, redis).await?,
+                };
 
-            Ok(Some(QuestionMessage { header, message }))
-        } else {
-            Ok(None)
+                Ok(Some(QuestionMessage {
+                    idx,
+                    total: QuestionOrder::length(&room, pack_len),
+                    pack,
+                    position,
+                    language,
+                }))
+            }
+            _ => Ok(None),
         }
     }
 
-    pub fn get_by_room_id(
+    pub async fn get_by_room_id(
         room_id: &String,
-        redis: &mut redis::Connection,
+        role: &str,
+        redis: &mut RedisConnection,
     ) -> Result<Option<QuestionMessage>, redis::RedisError> {
-        let room_key = Room::key(room_id, redis)?;
-        let pack: Option<String> = redis.hget(&room_key, "pack")?;
-        let idx: Option<u16> = redis.hget(&room_key, "idx")?;
-
-        match (pack, idx) {
-            (Some(pack), Some(idx)) => QuestionMessage::get(&pack, idx, redis),
+        match Room::question_idx(room_id, role, redis).await? {
+            Some(idx) => QuestionMessage::get(room_id, idx, redis).await,
             _ => Ok(None),
         }
     }
 
+    /// Rating rows of the question message in the mode as `(typ, field, prompt)`.
+    fn rows(mode: &str) -> Vec<(u8, &'static str, &'static str)> {
+        match mode {
+            RoomMode::SOLO => vec![(1, "importance", Messages::ANSWER_SELF_IMPORTANCE)],
+            RoomMode::GUESS => vec![
+                (3, "guess", Messages::ANSWER_GUESS),
+                (1, "importance", Messages::ANSWER_IMPORTANCE),
+                (2, "evaluation", Messages::ANSWER_EVALUATION),
+            ],
+            RoomMode::ASYNC => vec![
+                (1, "importance", Messages::ANSWER_IMPORTANCE),
+                (2, "evaluation", Messages::ANSWER_SELF_EVALUATION),
+            ],
+            _ => vec![
+                (1, "importance", Messages::ANSWER_IMPORTANCE),
+                (2, "evaluation", Messages::ANSWER_EVALUATION),
+            ],
+        }
+    }
+
+    /// Text and keyboard of the question message for the role. The keyboard marks the chosen
+    /// ratings, gets the Next button once all of them are set and is removed when the role is ready.
+    async fn render(
+        &self,
+        room_id: &String,
+        role: &str,
+        locale: Locale,
+        redis: &mut RedisConnection,
+    ) -> Result<(String, Option<InlineKeyboardMarkup>), redis::RedisError> {
+        let mode = RoomMode::get(room_id, redis).await?;
+        let room_key = Room::key(room_id, redis).await?;
+        let room: HashMap<String, String> = redis.hgetall(&room_key).await?;
+        let rows = QuestionMessage::rows(&mode);
+
+        let mut text = self.create_text(locale, redis).await?;
+        text.push('\n');
+        for (i, (_, _, prompt)) in rows.iter().enumerate() {
+            text.push_str(&format!("\n{}. {}", i + 1, locale.text(prompt)));
+        }
+
+        if mode == RoomMode::CLASSIC || mode == RoomMode::GUESS {
+            let partner_role = Role::opposite(&role.to_string());
+            let partner_rated = rows
+                .iter()
+                .all(|(_, field, _)| room.contains_key(&format!("{}_{}", partner_role, field)));
+            let status = if room.contains_key(&format!("{}_ready_at", partner_role)) {
+                Messages::PARTNER_READY
+            } else if partner_rated {
+                Messages::PARTNER_RATED
+            } else {
+                Messages::PARTNER_ANSWERING
+            };
+            text.push_str(&format!("\n\n{}", locale.text(status)));
+        }
+
+        if room.contains_key(&format!("{}_ready_at", role)) {
+            return Ok((text, None));
+        }
+
+        let selected: Vec<(u8, Option<u8>)> = rows
+            .iter()
+            .map(|(typ, field, _)| {
+                let value = room.get(&format!("{}_{}", role, field));
+                (*typ, value.and_then(|x| x.parse().ok()))
+            })
+            .collect();
+        let next = ternary!(
+            selected.iter().all(|(_, value)| value.is_some()),
+            Some(locale),
+            None
+        );
+        let keyboard =
+            InlineKeyboardMarkup::question(&selected, room_id, self.idx, next, redis).await?;
+
+        Ok((text, Some(keyboard)))
+    }
+
     pub async fn send(
         &self,
         user_id: i32,
         room_id: &String,
-        redis: &mut redis::Connection,
+        redis: &mut RedisConnection,
         client: &Client,
         url: &str,
-    ) -> Result<(), Box<dyn std::error::Error>> {
-        Context::set_context(user_id, Context::IN_ROOM, redis)?;
+    ) -> BotResult<()> {
+        let role = Room::get_role_for_user(user_id, room_id, redis)
+            .await?
+            .unwrap_or(Role::CREATOR);
+        // a user coming back with all ratings set gets the Next button, which needs this context
+        let rated =
+            CallbackData::role_has_all_callback_keys(&role.to_string(), room_id, redis).await?;
+        let context = ternary!(rated, Context::WAITING_FOR_ANSWER, Context::IN_ROOM);
+        Context::set_context(user_id, context, redis).await?;
+        self.deliver(user_id, room_id, role, redis, client, url)
+            .await?;
+        let locale = Locale::get(user_id, redis).await?;
+
+        if RoomMode::get(room_id, redis).await? == RoomMode::SOLO {
+            send_message(
+                url,
+                &OutgoingKeyboardMessage::with_text(user_id, locale.text(Messages::SOLO_NOTE_HINT)),
+                client,
+            )
+            .await?;
+
+            return Ok(());
+        }
+
+        QuestionTimer::send_countdown(user_id, room_id, redis, client, url).await?;
+
+        Ok(())
+    }
+
+    /// Sends the question message with the current state of the ratings and remembers it
+    /// as the one to refresh, the keyboards of earlier messages are stripped.
+    pub(crate) async fn deliver(
+        &self,
+        user_id: i32,
+        room_id: &String,
+        role: &str,
+        redis: &mut RedisConnection,
+        client: &Client,
+        url: &str,
+    ) -> BotResult<()> {
+        QuestionKeyboards::strip(user_id, redis, client, url).await?;
+        let locale = Locale::get(user_id, redis).await?;
+        let (text, reply_markup) = self.render(room_id, role, locale, redis).await?;
 
         let message = OutgoingKeyboardMessage {
             chat_id: user_id,
-            text: self.create_text(),
-            reply_markup: None,
+            text,
+            reply_markup,
             parse_mode: Some("HTML".to_string()),
         };
-        let importance = OutgoingInlineKeyboardMessage::with_eval_keys(
-            user_id,
-            Messages::ANSWER_IMPORTANCE,
-            1,
-            room_id,
-        );
-        let evaluation = OutgoingInlineKeyboardMessage::with_eval_keys(
-            user_id,
-            Messages::ANSWER_EVALUATION,
-            2,
-            room_id,
-        );
+        let message_id = send_message(url, &message, client).await?;
+        QuestionKeyboards::remember(user_id, message_id, redis).await?;
+
+        let room_key = Room::key(room_id, redis).await?;
+        let _: () = redis
+            .hset(&room_key, format!("{}_message", role), message_id)
+            .await?;
 
-        send_message(url, &message, client).await?;
-        send_message(url, &importance, client).await?;
-        send_message(url, &evaluation, client).await?;
+        Ok(())
+    }
+
+    /// Edits the question message of the user after a rating is chosen or the partner status changes.
+    pub(crate) async fn refresh(
+        user_id: i32,
+        room_id: &String,
+        role: &str,
+        message_id: i32,
+        redis: &mut RedisConnection,
+        client: &Client,
+        edit_url: &str,
+    ) -> BotResult<()> {
+        if let Some(question_message) =
+            QuestionMessage::get_by_room_id(room_id, role, redis).await?
+        {
+            let locale = Locale::get(user_id, redis).await?;
+            let (text, reply_markup) = question_message
+                .render(room_id, role, locale, redis)
+                .await?;
+
+            let mut edited = EditedMessageText::new(user_id as i64, message_id, &text);
+            edited.reply_markup = reply_markup;
+            edited.parse_mode = Some("HTML".to_string());
+            edited.edit_with_url(edit_url, client).await?;
+        }
+
+        Ok(())
+    }
+
+    /// Refreshes the last question message sent to the role, e.g. to the partner of the user.
+    pub(crate) async fn refresh_role(
+        room_id: &String,
+        role: &str,
+        redis: &mut RedisConnection,
+        client: &Client,
+        edit_url: &str,
+    ) -> BotResult<()> {
+        let room_key = Room::key(room_id, redis).await?;
+        let (user_id, message_id): (Option<i32>, Option<i32>) = redis
+            .hget(
+                &room_key,
+                &[format!("{}_id", role), format!("{}_message", role)],
+            )
+            .await?;
+
+        if let (Some(user_id), Some(message_id)) = (user_id, message_id) {
+            if Unreachable::is(user_id, redis).await? {
+                return Ok(());
+            }
+
+            QuestionMessage::refresh(user_id, room_id, role, message_id, redis, client, edit_url)
+                .await?;
+        }
+
+        Ok(())
+    }
+
+    /// Updates the status line of the partner of the role after the role has rated or is ready.
+    /// A partner who is already waiting also gets a message, as edits come without a notification.
+    pub(crate) async fn show_progress(
+        room_id: &String,
+        role: &str,
+        redis: &mut RedisConnection,
+        client: &Client,
+        url: &str,
+    ) -> BotResult<()> {
+        let mode = RoomMode::get(room_id, redis).await?;
+        if mode != RoomMode::CLASSIC && mode != RoomMode::GUESS {
+            return Ok(());
+        }
+
+        let partner_role = Role::opposite(&role.to_string());
+        let edit_url = replace_tg_method(url, TgMethods::EDIT_MESSAGE_TEXT);
+        QuestionMessage::refresh_role(room_id, role, redis, client, &edit_url).await?;
+        QuestionMessage::refresh_role(room_id, &partner_role, redis, client, &edit_url).await?;
+
+        let room_key = Room::key(room_id, redis).await?;
+        let partner_id: Option<i32> = redis
+            .hget(&room_key, format!("{}_id", partner_role))
+            .await?;
+        let partner_ready: bool = redis
+            .hexists(&room_key, format!("{}_ready_at", partner_role))
+            .await?;
+
+        if let (Some(partner_id), true) = (partner_id, partner_ready) {
+            if Unreachable::is(partner_id, redis).await? {
+                return Ok(());
+            }
+
+            let locale = Locale::get(partner_id, redis).await?;
+            let message = OutgoingKeyboardMessage::with_text(
+                partner_id,
+                locale.text(Messages::PARTNER_RATED_NOTIFICATION),
+            );
+            send_message(url, &message, client).await?;
+        }
 
         Ok(())
     }
 }
 
 pub(crate) async fn send_question_messages(
-    user_ids: [i32; 2],
-    pack: &String,
+    user_ids: &[i32],
     idx: u16,
-    redis: &mut redis::Connection,
+    redis: &mut RedisConnection,
     client: &Client,
     url: &str,
     room_id: &String,
     ch_url: &String,
-) -> Result<(), Box<dyn std::error::Error>> {
-    let question_message = QuestionMessage::get(pack, idx, redis)?;
+) -> BotResult<()> {
+    let question_message = QuestionMessage::get(room_id, idx, redis).await?;
+    let reachable = Unreachable::reachable(user_ids, redis).await?;
 
-    if question_message.is_some() {
-        let question_message = question_message.unwrap();
+    if let Some(question_message) = question_message {
+        QuestionTimer::start(room_id, idx, redis).await?;
 
-        for &user_id in user_ids.iter() {
-            &question_message
+        for &user_id in reachable.iter() {
+            question_message
                 .send(user_id, room_id, redis, client, url)
                 .await?;
         }
     } else {
-        for &user_id in user_ids.iter() {
+        RoomEvent::record(RoomEvent::FINISHED);
+
+        for &user_id in reachable.iter() {
+            let locale = Locale::get(user_id, redis).await?;
             let final_message = OutgoingKeyboardMessage {
                 chat_id: user_id,
-                text: String::from(Messages::EVALUATING_RESULTS),
+                text: String::from(locale.text(Messages::EVALUATING_RESULTS)),
                 reply_markup: None,
                 parse_mode: None,
             };
 
-            Context::set_context(user_id, Context::WAITING_FOR_RESULTS, redis)?;
+            Context::set_context(user_id, Context::WAITING_FOR_RESULTS, redis).await?;
+            QuestionKeyboards::strip(user_id, redis, client, url).await?;
             send_message(url, &final_message, client).await?;
         }
 
+        if RoomMode::get(room_id, redis).await? == RoomMode::SOLO {
+            return send_solo_report(user_ids, redis, client, url, room_id, ch_url).await;
+        }
+
         let report = ReportData::get(room_id, client, ch_url).await?;
+        let guess_report = ternary!(
+            RoomMode::get(room_id, redis).await? == RoomMode::GUESS,
+            Some(GuessReportData::get(room_id, client, ch_url).await?),
+            None
+        );
 
-        for &user_id in user_ids.iter() {
-            let user_role = Role::get(user_id, redis)?;
-            let report_string = ternary!(
+        for &user_id in reachable.iter() {
+            let user_role = Role::get(user_id, redis).await?;
+            let locale = Locale::get(user_id, redis).await?;
+            let mut report_string = ternary!(
                 report.is_empty(),
-                Messages::ALL_QUESTIONS_NON_IMPORTANT.to_string(),
-                report.generate_report(&user_role)
+                locale
+                    .text(Messages::ALL_QUESTIONS_NON_IMPORTANT)
+                    .to_string(),
+                report.generate_report(&user_role, locale)
             );
+
+            if let Some(guess_report) = &guess_report {
+                report_string = format!(
+                    "{}\n\n{}",
+                    report_string,
+                    guess_report.generate_report(&user_role, locale)
+                );
+            }
             let message = OutgoingKeyboardMessage {
                 chat_id: user_id,
                 text: report_string,
-                reply_markup: Some(ReplyKeyboardMarkup {
-                    keyboard: Keys::welcome(),
-                    one_time_keyboard: true,
-                }),
+                reply_markup: Some(InlineKeyboardMarkup::welcome(locale)),
                 parse_mode: Some("HTML".to_string()),
             };
 
             send_message(url, &message, client).await?;
-            Context::reset(user_id, redis)?;
-            let _: Result<(), redis::RedisError> = redis.del(format!("user:{}:room", user_id));
+            Context::reset(user_id, redis).await?;
+            let _: Result<(), redis::RedisError> =
+                redis.del(format!("user:{}:room", user_id)).await;
         }
 
-        Room::clear(user_ids[0], user_ids[1], room_id, redis)?
+        Room::clear(user_ids, room_id, redis).await?
     }
 
     Ok(())
 }
 
+async fn send_solo_report(
+    user_ids: &[i32],
+    redis: &mut RedisConnection,
+    client: &Client,
+    url: &str,
+    room_id: &String,
+    ch_url: &String,
+) -> BotResult<()> {
+    for &user_id in user_ids.iter() {
+        let locale = Locale::get(user_id, redis).await?;
+        let report_string = SoloRun::report(room_id, locale, redis, client, ch_url).await?;
+        let message = OutgoingKeyboardMessage {
+            chat_id: user_id,
+            text: report_string.to_string(),
+            reply_markup: Some(InlineKeyboardMarkup::welcome(locale)),
+            parse_mode: Some("HTML".to_string()),
+        };
+
+        if !Unreachable::is(user_id, redis).await? {
+            send_message(url, &message, client).await?;
+        }
+        SoloRun::finish(user_id, room_id, redis).await?;
+    }
+
+    Room::clear(user_ids, room_id, redis).await?;
+
+    Ok(())
+}
+
 pub async fn send_message<T: Serialize + ?Sized>(
     url: &str,
     message: &T,
     client: &Client,
-) -> Result<i32, reqwest::Error> {
-    let res = client
-        .post(url)
-        .json(message)
-        .send()
-        .await?
-        .json::<SentMessageResponse>()
-        .await;
-
-    if res.is_err() {
-        log::error!("{:?}", res);
-    }
-
-    res.map(|x| match x {
-        SentMessageResponse {
-            ok: true,
-            result: Some(SentMessage { message_id: id }),
-            ..
-        } => id,
-        _ => 0,
-    })
+) -> Result<i32, SendError> {
+    sender::request::<T, SentMessage>(url, message, client)
+        .await
+        .map(|x| x.message_id)
 }
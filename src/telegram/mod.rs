@@ -1,3 +1,5 @@
+pub mod callback;
 pub mod helpers;
 pub mod messages;
+pub mod sender;
 pub mod structures;
@@ -0,0 +1,266 @@
+use crate::metrics::{TELEGRAM_DURATION, TELEGRAM_ERRORS};
+
+use lazy_static::lazy_static;
+use reqwest::Client;
+use serde::de::DeserializeOwned;
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::fmt;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+// Telegram allows about 30 messages per second overall and 1 per second in a chat
+const GLOBAL_RATE: f64 = 30.0;
+const GLOBAL_BURST: f64 = 30.0;
+const CHAT_RATE: f64 = 1.0;
+const CHAT_BURST: f64 = 3.0;
+
+const MAX_ATTEMPTS: u32 = 5;
+const BACKOFF_MS: u64 = 500;
+
+lazy_static! {
+    static ref LIMITER: Mutex<RateLimiter> = Mutex::new(RateLimiter::default());
+}
+
+/// Errors of a Telegram request which are not worth retrying.
+#[derive(Debug)]
+pub enum SendError {
+    /// The user has blocked the bot or deleted the account.
+    Blocked(i64),
+    Api {
+        code: u16,
+        description: String,
+    },
+    /// Description of the failure, without the url which carries the bot token.
+    Network(String),
+}
+
+impl fmt::Display for SendError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            SendError::Blocked(chat_id) => write!(f, "chat {} is unreachable", chat_id),
+            SendError::Api { code, description } => {
+                write!(f, "Telegram error {}: {}", code, description)
+            }
+            SendError::Network(e) => write!(f, "Telegram request failed: {}", e),
+        }
+    }
+}
+
+impl std::error::Error for SendError {}
+
+impl SendError {
+    pub(crate) fn is_not_modified(&self) -> bool {
+        matches!(self, SendError::Api { description, .. } if description.contains("message is not modified"))
+    }
+}
+
+#[derive(Deserialize, Debug)]
+struct TgResponse<T> {
+    ok: bool,
+    result: Option<T>,
+    error_code: Option<u16>,
+    description: Option<String>,
+    parameters: Option<ResponseParameters>,
+}
+
+#[derive(Deserialize, Debug)]
+struct ResponseParameters {
+    retry_after: Option<u64>,
+}
+
+struct Bucket {
+    tokens: f64,
+    updated: Instant,
+}
+
+impl Bucket {
+    fn new(burst: f64) -> Bucket {
+        Bucket {
+            tokens: burst,
+            updated: Instant::now(),
+        }
+    }
+
+    /// Takes a token, possibly borrowing it from the future, and returns how long to wait for it.
+    fn take(&mut self, rate: f64, burst: f64, now: Instant) -> Duration {
+        let elapsed = now.duration_since(self.updated).as_secs_f64();
+        self.tokens = (self.tokens + elapsed * rate).min(burst) - 1.0;
+        self.updated = now;
+
+        Duration::from_secs_f64((-self.tokens / rate).max(0.0))
+    }
+}
+
+struct RateLimiter {
+    global: Bucket,
+    chats: HashMap<i64, Bucket>,
+}
+
+impl Default for RateLimiter {
+    fn default() -> RateLimiter {
+        RateLimiter {
+            global: Bucket::new(GLOBAL_BURST),
+            chats: HashMap::new(),
+        }
+    }
+}
+
+impl RateLimiter {
+    fn reserve(&mut self, chat_id: Option<i64>) -> Duration {
+        let now = Instant::now();
+        let global_wait = self.global.take(GLOBAL_RATE, GLOBAL_BURST, now);
+
+        // buckets which have refilled carry no state
+        if self.chats.len() > 10_000 {
+            let refill = Duration::from_secs_f64(CHAT_BURST / CHAT_RATE);
+            self.chats
+                .retain(|_, bucket| now.duration_since(bucket.updated) < refill);
+        }
+
+        let chat_wait = match chat_id {
+            Some(chat_id) => self
+                .chats
+                .entry(chat_id)
+                .or_insert_with(|| Bucket::new(CHAT_BURST))
+                .take(CHAT_RATE, CHAT_BURST, now),
+            None => Duration::from_secs(0),
+        };
+
+        global_wait.max(chat_wait)
+    }
+}
+
+/// Calls a Telegram method within the rate limits. Network errors, server errors and
+/// `429 Too Many Requests` are retried, honoring `retry_after`; other errors are returned as is.
+pub(crate) async fn request<B: Serialize + ?Sized, T: DeserializeOwned>(
+    url: &str,
+    body: &B,
+    client: &Client,
+) -> Result<T, SendError> {
+    let body = serde_json::to_value(body).map_err(|e| SendError::Api {
+        code: 0,
+        description: e.to_string(),
+    })?;
+    let chat_id = body.get("chat_id").and_then(|x| x.as_i64());
+    let mut attempt = 1;
+
+    loop {
+        let wait = LIMITER.lock().unwrap().reserve(chat_id);
+        if wait > Duration::from_secs(0) {
+            tokio::time::sleep(wait).await;
+        }
+
+        let started = Instant::now();
+        let response = send(url, &body, client).await;
+        TELEGRAM_DURATION
+            .with_label_values(&[url_method(url)])
+            .observe(started.elapsed().as_secs_f64());
+
+        let (delay, error) = match response {
+            Ok(response) if response.ok => {
+                return response.result.ok_or_else(|| SendError::Api {
+                    code: 0,
+                    description: "no result in the response".to_string(),
+                });
+            }
+            Ok(response) => {
+                let code = response.error_code.unwrap_or(0);
+                let description = response.description.unwrap_or_default();
+                let retry_after = response.parameters.and_then(|x| x.retry_after);
+                TELEGRAM_ERRORS
+                    .with_label_values(&[url_method(url), &code.to_string()])
+                    .inc();
+                let delay = match (code, retry_after) {
+                    (429, Some(retry_after)) => Duration::from_secs(retry_after),
+                    (code, _) if code >= 500 || code == 429 => backoff(attempt),
+                    (403, _) => {
+                        return Err(match chat_id {
+                            Some(chat_id) => SendError::Blocked(chat_id),
+                            None => SendError::Api { code, description },
+                        })
+                    }
+                    _ => return Err(SendError::Api { code, description }),
+                };
+
+                (delay, SendError::Api { code, description })
+            }
+            Err(e) => {
+                TELEGRAM_ERRORS
+                    .with_label_values(&[url_method(url), "network"])
+                    .inc();
+
+                if e.is_builder() {
+                    return Err(SendError::Network(network_failure(&e)));
+                }
+                (backoff(attempt), SendError::Network(network_failure(&e)))
+            }
+        };
+
+        if attempt >= MAX_ATTEMPTS {
+            return Err(error);
+        }
+
+        tracing::warn!(method = url_method(url), ?delay, "Retrying");
+        tokio::time::sleep(delay).await;
+        attempt += 1;
+    }
+}
+
+/// Edits a message. Old messages can't be edited and unchanged ones are rejected by Telegram,
+/// so API errors are only logged.
+pub(crate) async fn edit<B: Serialize + ?Sized>(
+    url: &str,
+    body: &B,
+    client: &Client,
+) -> Result<(), SendError> {
+    match request::<B, serde_json::Value>(url, body, client).await {
+        Err(e) if e.is_not_modified() => Ok(()),
+        Err(e @ SendError::Api { .. }) => {
+            tracing::warn!("{}", e);
+            Ok(())
+        }
+        result => result.map(|_| ()),
+    }
+}
+
+async fn send<T: DeserializeOwned>(
+    url: &str,
+    body: &serde_json::Value,
+    client: &Client,
+) -> Result<TgResponse<T>, reqwest::Error> {
+    client.post(url).json(body).send().await?.json().await
+}
+
+fn backoff(attempt: u32) -> Duration {
+    Duration::from_millis(BACKOFF_MS * 2u64.pow(attempt - 1))
+}
+
+// the error display of reqwest includes the url, so it is described by its kind and cause
+pub(crate) fn network_failure(e: &reqwest::Error) -> String {
+    if let Some(status) = e.status() {
+        return format!("unexpected status {}", status);
+    }
+
+    let kind = if e.is_timeout() {
+        "timed out"
+    } else if e.is_connect() {
+        "connection failed"
+    } else if e.is_decode() {
+        "unexpected response"
+    } else if e.is_builder() {
+        "invalid request"
+    } else {
+        "network error"
+    };
+
+    match std::error::Error::source(e) {
+        Some(source) => format!("{}: {}", kind, source),
+        None => kind.to_string(),
+    }
+}
+
+// urls contain the bot token, so only the method is logged
+fn url_method(url: &str) -> &str {
+    url.rsplit('/').next().unwrap_or_default()
+}
@@ -1,10 +1,20 @@
 use crate::bot::constants::*;
+use crate::bot::locale::Locale;
+use crate::bot::order::QuestionOrder;
+use crate::bot::pack::Pack;
 use crate::bot::room::*;
+use crate::bot::scripts::SET_RATING;
+use crate::bot::timer::QuestionTimer;
+use crate::error::BotResult;
+use crate::settings::settings;
+use crate::storage::RedisConnection;
+use crate::telegram::callback::CallbackPayload;
 use crate::telegram::helpers::*;
-use crate::telegram::messages::send_message;
+use crate::telegram::messages::QuestionMessage;
+use crate::telegram::sender::{self, SendError};
 use crate::ternary;
 
-use redis::Commands;
+use redis::AsyncCommands;
 use reqwest::Client;
 use serde::{Deserialize, Serialize};
 
@@ -13,36 +23,52 @@ impl TgMethods {
     pub const GET_UPDATES: &'static str = "getUpdates";
     pub const SEND_MESSAGE: &'static str = "sendMessage";
     pub const EDIT_MESSAGE_REPLY_MARKUP: &'static str = "editMessageReplyMarkup";
+    pub const EDIT_MESSAGE_TEXT: &'static str = "editMessageText";
     pub const ANSWER_CALLBACK_QUERY: &'static str = "answerCallbackQuery";
 }
 
-#[derive(Deserialize, Debug)]
-pub struct TgResult {
-    pub result: Vec<TgUpdate>,
-}
-
-#[derive(Deserialize, Debug)]
-pub struct TgError {
-    pub description: String,
-}
-
-#[derive(Deserialize, Debug)]
-pub struct InlineQuery {
-    query: String,
-}
-
 #[derive(Deserialize, Debug)]
 pub struct CallbackQuery {
     id: String,
+    from: TgUser,
     message: Option<TgMessage>,
     data: Option<String>,
 }
 
+impl CallbackQuery {
+    pub(crate) async fn reject(
+        &self,
+        text: &str,
+        bot_token: &str,
+        client: &Client,
+    ) -> BotResult<()> {
+        answer_callback_query(
+            bot_token,
+            client,
+            self.id.to_string(),
+            Some(text.to_string()),
+        )
+        .await
+    }
+
+    /// Answers a navigation callback and removes the buttons, so that they can't be pressed twice.
+    pub(crate) async fn acknowledge(&self, bot_token: &str, client: &Client) -> BotResult<()> {
+        if let Some(message) = &self.message {
+            EditedReplyInlineMarkup::remove(message.chat.id, message.message_id)
+                .edit(bot_token, client)
+                .await?;
+        }
+
+        answer_callback_query(bot_token, client, self.id.to_string(), None).await
+    }
+}
+
 #[derive(Deserialize, Debug)]
 pub struct TgUser {
     pub(crate) id: i32,
     first_name: String,
     last_name: Option<String>,
+    pub(crate) language_code: Option<String>,
 }
 
 #[derive(Deserialize, Debug)]
@@ -50,18 +76,12 @@ pub struct TgChat {
     id: i64,
 }
 
-#[derive(Serialize, Debug)]
-pub struct ReplyKeyboardMarkup {
-    pub(crate) keyboard: Vec<Vec<String>>,
-    pub(crate) one_time_keyboard: bool,
-}
-
 #[derive(Serialize, Debug)]
 pub struct OutgoingKeyboardMessage {
     pub(crate) chat_id: i32,
     pub(crate) text: String,
     #[serde(skip_serializing_if = "Option::is_none")]
-    pub(crate) reply_markup: Option<ReplyKeyboardMarkup>,
+    pub(crate) reply_markup: Option<InlineKeyboardMarkup>,
     #[serde(skip_serializing_if = "Option::is_none")]
     pub(crate) parse_mode: Option<String>,
 }
@@ -79,64 +99,137 @@ impl OutgoingKeyboardMessage {
     pub(crate) fn with_keyboard(
         chat_id: i32,
         text: &str,
-        keyboard: Vec<Vec<String>>,
+        keyboard: InlineKeyboardMarkup,
     ) -> OutgoingKeyboardMessage {
         OutgoingKeyboardMessage {
             chat_id,
             text: String::from(text),
-            reply_markup: Some(ReplyKeyboardMarkup {
-                keyboard,
-                one_time_keyboard: true,
-            }),
+            reply_markup: Some(keyboard),
             parse_mode: None,
         }
     }
 
-    pub(crate) fn welcome_message(chat_id: i32) -> OutgoingKeyboardMessage {
-        OutgoingKeyboardMessage::with_keyboard(chat_id, Messages::WELCOME, Keys::welcome())
+    pub(crate) fn welcome_message(chat_id: i32, locale: Locale) -> OutgoingKeyboardMessage {
+        OutgoingKeyboardMessage::with_keyboard(
+            chat_id,
+            locale.text(Messages::WELCOME),
+            InlineKeyboardMarkup::welcome(locale),
+        )
+    }
+
+    pub(crate) fn partner_unreachable(
+        chat_id: i32,
+        finished: bool,
+        locale: Locale,
+    ) -> OutgoingKeyboardMessage {
+        OutgoingKeyboardMessage::with_keyboard(
+            chat_id,
+            locale.text(ternary!(
+                finished,
+                Messages::PARTNER_UNREACHABLE_FINISHED,
+                Messages::PARTNER_UNREACHABLE
+            )),
+            InlineKeyboardMarkup::partner_unreachable(finished, locale),
+        )
     }
 
-    pub(crate) fn join_room(chat_id: i32) -> OutgoingKeyboardMessage {
-        OutgoingKeyboardMessage::with_text(chat_id, Messages::INSERT_ROOM_ID)
+    pub(crate) fn join_room(chat_id: i32, locale: Locale) -> OutgoingKeyboardMessage {
+        OutgoingKeyboardMessage::with_text(chat_id, locale.text(Messages::INSERT_ROOM_ID))
     }
 
-    pub(crate) fn create_select_pack(chat_id: i32, packs: Vec<String>) -> OutgoingKeyboardMessage {
+    pub(crate) fn create_select_pack(
+        chat_id: i32,
+        packs: Vec<String>,
+        locale: Locale,
+    ) -> OutgoingKeyboardMessage {
         OutgoingKeyboardMessage::with_keyboard(
             chat_id,
-            Messages::CHOOSE_PACK,
-            packs.iter().map(|pack| vec![pack.to_string()]).collect(),
+            locale.text(Messages::CHOOSE_PACK),
+            InlineKeyboardMarkup::packs(&packs),
         )
     }
 
-    pub(crate) fn no_room_id_in_message(chat_id: i32) -> OutgoingKeyboardMessage {
-        OutgoingKeyboardMessage::with_text(chat_id, Messages::NO_ROOM_ID_IN_MESSAGE)
+    pub(crate) fn select_pack_language(
+        chat_id: i32,
+        languages: &[Locale],
+        locale: Locale,
+    ) -> OutgoingKeyboardMessage {
+        OutgoingKeyboardMessage::with_keyboard(
+            chat_id,
+            locale.text(Messages::CHOOSE_PACK_LANGUAGE),
+            InlineKeyboardMarkup::pack_languages(languages),
+        )
     }
 
-    pub(crate) fn wrong_room_id(chat_id: i32) -> OutgoingKeyboardMessage {
-        OutgoingKeyboardMessage::with_text(chat_id, Messages::WRONG_ROOM_ID)
+    pub(crate) fn select_order(chat_id: i32, locale: Locale) -> OutgoingKeyboardMessage {
+        OutgoingKeyboardMessage::with_keyboard(
+            chat_id,
+            &QuestionOrder::text(Messages::CHOOSE_ORDER, locale),
+            InlineKeyboardMarkup::orders(locale),
+        )
     }
 
-    pub(crate) fn error(chat_id: i32) -> OutgoingKeyboardMessage {
-        OutgoingKeyboardMessage::with_text(chat_id, Messages::ERROR)
+    pub(crate) fn select_timer(chat_id: i32, locale: Locale) -> OutgoingKeyboardMessage {
+        OutgoingKeyboardMessage::with_keyboard(
+            chat_id,
+            locale.text(Messages::CHOOSE_TIMER),
+            InlineKeyboardMarkup::timers(locale),
+        )
     }
 
-    pub(crate) fn internal_error(chat_id: i32) -> OutgoingKeyboardMessage {
-        OutgoingKeyboardMessage::with_text(chat_id, Messages::ERROR_INTERNAL)
+    pub(crate) fn no_room_id_in_message(chat_id: i32, locale: Locale) -> OutgoingKeyboardMessage {
+        OutgoingKeyboardMessage::with_text(chat_id, locale.text(Messages::NO_ROOM_ID_IN_MESSAGE))
     }
 
-    pub(crate) fn room_id_message(chat_id: i32, room_id: &String) -> OutgoingKeyboardMessage {
-        OutgoingKeyboardMessage::with_text(
+    pub(crate) fn wrong_room_id(chat_id: i32, locale: Locale) -> OutgoingKeyboardMessage {
+        OutgoingKeyboardMessage::with_text(chat_id, locale.text(Messages::WRONG_ROOM_ID))
+    }
+
+    pub(crate) fn room_taken(chat_id: i32, locale: Locale) -> OutgoingKeyboardMessage {
+        OutgoingKeyboardMessage::with_text(chat_id, locale.text(Messages::ROOM_TAKEN))
+    }
+
+    pub(crate) fn error(chat_id: i32, locale: Locale) -> OutgoingKeyboardMessage {
+        OutgoingKeyboardMessage::with_text(chat_id, locale.text(Messages::ERROR))
+    }
+
+    pub(crate) fn room_id_message(
+        chat_id: i32,
+        room_id: &str,
+        locale: Locale,
+    ) -> OutgoingKeyboardMessage {
+        OutgoingKeyboardMessage::with_room_id(
             chat_id,
-            format!("{}\nID комнаты: {}", Messages::WAITING_FOR_PARTNER, room_id).as_str(),
+            Messages::WAITING_FOR_PARTNER,
+            room_id,
+            locale,
         )
     }
-}
 
-#[derive(Deserialize, Debug)]
-pub struct SentMessageResponse {
-    pub ok: bool,
-    pub result: Option<SentMessage>,
-    pub description: Option<String>,
+    /// The creator of an async room starts right away, the partner joins later.
+    pub(crate) fn async_start_message(
+        chat_id: i32,
+        room_id: &str,
+        locale: Locale,
+    ) -> OutgoingKeyboardMessage {
+        OutgoingKeyboardMessage::with_room_id(chat_id, Messages::ASYNC_START, room_id, locale)
+    }
+
+    fn with_room_id(
+        chat_id: i32,
+        key: &'static str,
+        room_id: &str,
+        locale: Locale,
+    ) -> OutgoingKeyboardMessage {
+        OutgoingKeyboardMessage::with_text(
+            chat_id,
+            &format!(
+                "{}\n{}",
+                locale.text(key),
+                locale.format(Messages::ROOM_ID, &[("room_id", room_id.to_string())])
+            ),
+        )
+    }
 }
 
 #[derive(Deserialize, Debug)]
@@ -154,80 +247,216 @@ async fn answer_callback_query(
     bot_token: &str,
     client: &Client,
     callback_query_id: String,
-    idx: u8,
-    typ: u8,
-) -> Result<(), Box<dyn std::error::Error>> {
+    text: Option<String>,
+) -> BotResult<()> {
     let url = create_tg_url(bot_token, TgMethods::ANSWER_CALLBACK_QUERY);
-    let pack = ternary!(typ == 1, IMPORTANCE_EMOJIS, EVALUATION_EMOJIS);
 
     let answer = CallbackQueryAnswer {
         callback_query_id,
-        text: pack
-            .get(idx as usize)
-            .map(|x: &&str| format!("Оценка: {}", x)),
+        text,
     };
 
-    client.post(&url).json(&answer).send().await?;
+    // answering is best effort, e.g. Telegram rejects answers to old callbacks
+    match sender::request::<_, bool>(&url, &answer, client).await {
+        Err(e @ SendError::Api { .. }) => tracing::warn!("{}", e),
+        result => {
+            result?;
+        }
+    }
 
     Ok(())
 }
 
 #[derive(Serialize, Debug)]
-pub struct OutgoingInlineKeyboardMessage {
-    chat_id: i32,
-    text: String,
-    #[serde(skip_serializing_if = "Option::is_none")]
-    reply_markup: Option<InlineKeyboardMarkup>,
+pub struct InlineKeyboardMarkup {
+    inline_keyboard: Vec<Vec<InlineKeyboardButton>>,
 }
 
-impl OutgoingInlineKeyboardMessage {
-    fn create_eval_keys(
+impl InlineKeyboardMarkup {
+    fn row(locale: Locale, nav: u8, keys: &[(&'static str, u8)]) -> Vec<InlineKeyboardButton> {
+        keys.iter()
+            .map(|&(key, value)| InlineKeyboardButton::navigation(locale.text(key), nav, value))
+            .collect()
+    }
+
+    pub(crate) fn welcome(locale: Locale) -> InlineKeyboardMarkup {
+        let create = |key: &'static str, mode: &str| {
+            let value = RoomMode::ALL.iter().position(|&x| x == mode).unwrap_or(0);
+            InlineKeyboardButton::navigation(locale.text(key), NavigationData::CREATE, value as u8)
+        };
+
+        InlineKeyboardMarkup {
+            inline_keyboard: vec![
+                vec![
+                    create(Keys::CREATE, RoomMode::CLASSIC),
+                    InlineKeyboardButton::navigation(
+                        locale.text(Keys::JOIN),
+                        NavigationData::JOIN,
+                        0,
+                    ),
+                ],
+                vec![
+                    create(Keys::CREATE_GUESS, RoomMode::GUESS),
+                    create(Keys::CREATE_ASYNC, RoomMode::ASYNC),
+                    create(Keys::CREATE_SOLO, RoomMode::SOLO),
+                ],
+            ],
+        }
+    }
+
+    pub(crate) fn packs(packs: &[String]) -> InlineKeyboardMarkup {
+        InlineKeyboardMarkup {
+            inline_keyboard: packs
+                .iter()
+                .map(|pack| {
+                    vec![InlineKeyboardButton {
+                        text: pack.to_string(),
+                        callback_data: CallbackPayload::encode_navigation(&NavigationData {
+                            nav: NavigationData::PACK,
+                            value: 0,
+                            pack: Some(pack.to_string()),
+                        }),
+                    }]
+                })
+                .collect(),
+        }
+    }
+
+    pub(crate) fn pack_languages(languages: &[Locale]) -> InlineKeyboardMarkup {
+        let buttons = languages
+            .iter()
+            .map(|language| {
+                let value = Locale::ALL.iter().position(|x| x == language).unwrap_or(0);
+                InlineKeyboardButton::navigation(
+                    language.name(),
+                    NavigationData::PACK_LANGUAGE,
+                    value as u8,
+                )
+            })
+            .collect();
+
+        InlineKeyboardMarkup {
+            inline_keyboard: vec![buttons],
+        }
+    }
+
+    pub(crate) fn orders(locale: Locale) -> InlineKeyboardMarkup {
+        let mut buttons = QuestionOrder::KEYS
+            .iter()
+            .enumerate()
+            .map(|(i, &key)| {
+                InlineKeyboardButton::navigation(
+                    &QuestionOrder::text(key, locale),
+                    NavigationData::ORDER,
+                    i as u8,
+                )
+            })
+            .collect::<Vec<_>>();
+        let second_row = buttons.split_off(2);
+
+        InlineKeyboardMarkup {
+            inline_keyboard: vec![buttons, second_row],
+        }
+    }
+
+    pub(crate) fn timers(locale: Locale) -> InlineKeyboardMarkup {
+        let mut buttons = QuestionTimer::options()
+            .iter()
+            .enumerate()
+            .map(|(i, &seconds)| {
+                InlineKeyboardButton::navigation(
+                    &QuestionTimer::label(seconds, locale),
+                    NavigationData::TIMER,
+                    i as u8,
+                )
+            })
+            .collect::<Vec<_>>();
+        let timed = buttons.split_off(1);
+        let mut inline_keyboard = vec![buttons];
+
+        if !timed.is_empty() {
+            inline_keyboard.push(timed);
+        }
+
+        InlineKeyboardMarkup { inline_keyboard }
+    }
+
+    pub(crate) fn partner_unreachable(finished: bool, locale: Locale) -> InlineKeyboardMarkup {
+        let keys: &[(&'static str, u8)] = ternary!(
+            finished,
+            &[(Keys::LEAVE_ROOM, 1)],
+            &[(Keys::CONTINUE_ALONE, 0), (Keys::LEAVE_ROOM, 1)]
+        );
+
+        InlineKeyboardMarkup {
+            inline_keyboard: vec![InlineKeyboardMarkup::row(
+                locale,
+                NavigationData::UNREACHABLE,
+                keys,
+            )],
+        }
+    }
+
+    async fn create_eval_keys(
         typ: u8,
         selected_key: Option<u8>,
-        room_id: &String,
-    ) -> Vec<InlineKeyboardButton> {
+        room_id: &str,
+        question: u16,
+        redis: &mut RedisConnection,
+    ) -> redis::RedisResult<Vec<InlineKeyboardButton>> {
         let selected_idx = selected_key.unwrap_or(99);
-        let pack = ternary!(typ == 1, IMPORTANCE_EMOJIS, EVALUATION_EMOJIS);
+        let pack = CallbackData::emojis(typ);
+        let mut keys = vec![];
+
+        for (i, x) in pack.iter().enumerate() {
+            let data = CallbackData {
+                idx: i as u8,
+                typ,
+                room_id: room_id.to_string(),
+                question: Some(question),
+            };
 
-        pack.iter()
-            .enumerate()
-            .map(|(i, &x)| InlineKeyboardButton {
+            keys.push(InlineKeyboardButton {
                 text: ternary!(
                     i == selected_idx as usize,
                     format!("({})", x),
-                    format!("{}", x)
+                    x.to_string()
                 ),
-                callback_data: serde_json::to_string(&CallbackData {
-                    idx: i as u8,
-                    typ,
-                    room_id: room_id.clone(),
-                })
-                .unwrap(),
-            })
-            .collect()
+                callback_data: CallbackPayload::encode_rating(&data, question, redis).await?,
+            });
+        }
+
+        Ok(keys)
     }
 
-    pub(crate) fn with_eval_keys(
-        chat_id: i32,
-        text: &str,
-        typ: u8,
-        room_id: &String,
-    ) -> OutgoingInlineKeyboardMessage {
-        let keys = OutgoingInlineKeyboardMessage::create_eval_keys(typ, None, room_id);
+    /// Keyboard of a question message: a row of rating buttons for each `(typ, selected)`
+    /// and the Next button if `next` is set.
+    pub(crate) async fn question(
+        rows: &[(u8, Option<u8>)],
+        room_id: &str,
+        question: u16,
+        next: Option<Locale>,
+        redis: &mut RedisConnection,
+    ) -> redis::RedisResult<InlineKeyboardMarkup> {
+        let mut inline_keyboard = vec![];
+
+        for &(typ, selected) in rows.iter() {
+            inline_keyboard.push(
+                InlineKeyboardMarkup::create_eval_keys(typ, selected, room_id, question, redis)
+                    .await?,
+            );
+        }
 
-        OutgoingInlineKeyboardMessage {
-            chat_id,
-            text: text.parse().unwrap(),
-            reply_markup: Some(InlineKeyboardMarkup {
-                inline_keyboard: vec![keys],
-            }),
+        if let Some(locale) = next {
+            inline_keyboard.push(InlineKeyboardMarkup::row(
+                locale,
+                NavigationData::READY,
+                &[(Keys::READY, 0)],
+            ));
         }
-    }
-}
 
-#[derive(Serialize, Debug)]
-pub struct InlineKeyboardMarkup {
-    inline_keyboard: Vec<Vec<InlineKeyboardButton>>,
+        Ok(InlineKeyboardMarkup { inline_keyboard })
+    }
 }
 
 #[derive(Deserialize, Debug)]
@@ -235,11 +464,38 @@ pub struct TgMessage {
     message_id: i32,
     pub(crate) from: TgUser,
     chat: TgChat,
-    date: i32,
     pub(crate) text: Option<String>,
     entities: Option<Vec<MessageEntity>>,
 }
 
+impl TgMessage {
+    /// Commands are logged as is, other texts and the names of the sender only with `log_private`.
+    pub(crate) fn log(&self) {
+        let text = self.text.as_deref().unwrap_or_default();
+        let command = text
+            .split_whitespace()
+            .next()
+            .filter(|x| x.starts_with('/'));
+
+        if settings().log_private {
+            tracing::info!(
+                message_id = self.message_id,
+                text,
+                first_name = %self.from.first_name,
+                last_name = self.from.last_name.as_deref(),
+                "Message"
+            );
+        } else {
+            tracing::info!(
+                message_id = self.message_id,
+                command,
+                text_length = text.chars().count(),
+                "Message"
+            );
+        }
+    }
+}
+
 #[derive(Deserialize, Debug)]
 pub struct MessageEntity {
     #[serde(rename = "type")]
@@ -252,6 +508,19 @@ pub struct InlineKeyboardButton {
     callback_data: String,
 }
 
+impl InlineKeyboardButton {
+    fn navigation(text: &str, nav: u8, value: u8) -> InlineKeyboardButton {
+        InlineKeyboardButton {
+            text: text.to_string(),
+            callback_data: CallbackPayload::encode_navigation(&NavigationData {
+                nav,
+                value,
+                pack: None,
+            }),
+        }
+    }
+}
+
 #[derive(Serialize, Debug)]
 pub struct EditedReplyInlineMarkup {
     chat_id: i64,
@@ -260,23 +529,63 @@ pub struct EditedReplyInlineMarkup {
 }
 
 impl EditedReplyInlineMarkup {
-    async fn edit(&self, bot_token: &str, client: &Client) -> Result<(), reqwest::Error> {
+    pub(crate) fn remove(chat_id: i64, message_id: i32) -> EditedReplyInlineMarkup {
+        EditedReplyInlineMarkup {
+            chat_id,
+            message_id,
+            reply_markup: None,
+        }
+    }
+
+    async fn edit(&self, bot_token: &str, client: &Client) -> Result<(), SendError> {
         let url = create_tg_url(bot_token, TgMethods::EDIT_MESSAGE_REPLY_MARKUP);
-        let response = client.post(&url).json(self).send().await;
+        self.edit_with_url(&url, client).await
+    }
 
-        if response.is_err() {
-            log::error!("{:?}", response);
+    pub(crate) async fn edit_with_url(&self, url: &str, client: &Client) -> Result<(), SendError> {
+        sender::edit(url, self, client).await
+    }
+}
+
+#[derive(Serialize, Debug)]
+pub struct EditedMessageText {
+    chat_id: i64,
+    message_id: i32,
+    text: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub(crate) reply_markup: Option<InlineKeyboardMarkup>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub(crate) parse_mode: Option<String>,
+}
+
+impl EditedMessageText {
+    pub(crate) fn new(chat_id: i64, message_id: i32, text: &str) -> EditedMessageText {
+        EditedMessageText {
+            chat_id,
+            message_id,
+            text: text.to_string(),
+            reply_markup: None,
+            parse_mode: None,
         }
+    }
 
-        Ok(())
+    pub(crate) async fn edit(&self, bot_token: &str, client: &Client) -> Result<(), SendError> {
+        let url = create_tg_url(bot_token, TgMethods::EDIT_MESSAGE_TEXT);
+        self.edit_with_url(&url, client).await
+    }
+
+    pub(crate) async fn edit_with_url(&self, url: &str, client: &Client) -> Result<(), SendError> {
+        sender::edit(url, self, client).await
     }
 }
 
-#[derive(Deserialize, Serialize, Debug)]
+#[derive(Debug)]
 pub struct CallbackData {
-    idx: u8,
-    typ: u8,
-    room_id: String,
+    pub(crate) idx: u8,
+    pub(crate) typ: u8,
+    pub(crate) room_id: String,
+    // question the buttons were sent for, unknown for unsigned buttons
+    pub(crate) question: Option<u16>,
 }
 
 impl CallbackData {
@@ -284,50 +593,79 @@ impl CallbackData {
         match self.typ {
             1 => CallbackMessageType::Importance,
             2 => CallbackMessageType::Evaluation,
+            3 => CallbackMessageType::Guess,
             _ => CallbackMessageType::Error,
         }
     }
 
-    fn role_has_all_callback_keys(
+    fn emojis(typ: u8) -> &'static [String; 5] {
+        let ratings = &settings().ratings;
+        ternary!(typ == 2, &ratings.evaluation, &ratings.importance)
+    }
+
+    fn answer_text(&self, locale: Locale) -> Option<String> {
+        let key = match self.match_type() {
+            CallbackMessageType::Guess => Messages::CALLBACK_GUESS,
+            _ => Messages::CALLBACK_RATING,
+        };
+
+        CallbackData::emojis(self.typ)
+            .get(self.idx as usize)
+            .map(|x| locale.format(key, &[("emoji", x.to_string())]))
+    }
+
+    pub(crate) async fn role_has_all_callback_keys(
         role: &String,
         room_id: &String,
-        redis: &mut redis::Connection,
+        redis: &mut RedisConnection,
     ) -> Result<bool, redis::RedisError> {
-        let set_values: Vec<i32> = redis.hget(
-            format!("room:{}", room_id),
-            &[
-                format!("{}_importance", role),
-                format!("{}_evaluation", role),
-            ],
-        )?;
+        let fields: Vec<String> = RoomMode::rating_fields(&RoomMode::get(room_id, redis).await?)
+            .iter()
+            .map(|field| format!("{}_{}", role, field))
+            .collect();
 
-        Ok(set_values.len() == 2)
+        let set_values: Vec<i32> = redis
+            .hget(format!("room:{}", room_id), fields.as_slice())
+            .await?;
+
+        Ok(set_values.len() == fields.len())
     }
 
-    fn set_value_for_role(
+    /// Sets the rating in one script, so that concurrent clicks can't both complete the ratings
+    /// or change a guess which is already made.
+    async fn set_value_for_role(
         role: &String,
         message_type: &CallbackMessageType,
         value: u8,
         room_id: &String,
-        redis: &mut redis::Connection,
-    ) -> Result<bool, redis::RedisError> {
+        redis: &mut RedisConnection,
+    ) -> Result<RatingUpdate, redis::RedisError> {
         if (role == Role::CREATOR || role == Role::VISITOR) && value < 5 {
             let redis_field = format!("{}_{}", role, format!("{:?}", message_type).to_lowercase()); // creator_importance, ...
-            let previous_has_all_keys =
-                CallbackData::role_has_all_callback_keys(role, room_id, redis)?;
-            let room_key = Room::key(room_id, redis)?;
-
-            redis.hset(room_key, redis_field, value)?;
-
-            let new_has_all_keys = CallbackData::role_has_all_callback_keys(role, room_id, redis)?;
+            let is_locked = matches!(message_type, CallbackMessageType::Guess);
+            let fields: Vec<String> =
+                RoomMode::rating_fields(&RoomMode::get(room_id, redis).await?)
+                    .iter()
+                    .map(|field| format!("{}_{}", role, field))
+                    .collect();
+            let room_key = Room::key(room_id, redis).await?;
+
+            let result: i32 = SET_RATING
+                .key(room_key)
+                .arg(redis_field)
+                .arg(value)
+                .arg(ternary!(is_locked, "1", "0"))
+                .arg(fields)
+                .invoke_async(redis)
+                .await?;
 
-            if !previous_has_all_keys && new_has_all_keys {
-                Ok(true)
-            } else {
-                Ok(false)
-            }
+            Ok(match result {
+                -1 => RatingUpdate::Locked,
+                1 => RatingUpdate::Completed,
+                _ => RatingUpdate::Set,
+            })
         } else {
-            Ok(false)
+            Ok(RatingUpdate::Ignored)
         }
     }
 
@@ -336,97 +674,214 @@ impl CallbackData {
         id: &String,
         user_id: i64,
         message_id: i32,
-        redis: &mut redis::Connection,
+        redis: &mut RedisConnection,
         client: &Client,
         bot_token: &str,
-    ) -> Result<(), Box<dyn std::error::Error>> {
-        let context = Context::get(user_id as i32, redis)?;
+    ) -> BotResult<()> {
+        let context = Context::get(user_id as i32, redis).await?;
+        let locale = Locale::get(user_id as i32, redis).await?;
 
         if context == Context::IN_ROOM || context == Context::WAITING_FOR_ANSWER {
-            let user_role = Room::get_role_for_user(user_id as i32, &self.room_id, redis)?;
+            let user_role = Room::get_role_for_user(user_id as i32, &self.room_id, redis).await?;
+            let question = match user_role {
+                Some(role) => Room::question_idx(&self.room_id, role, redis).await?,
+                None => None,
+            };
 
-            let message_type = self.match_type();
+            // ratings are only accepted for the question the buttons were sent for
+            if question.is_none() || self.question != question {
+                EditedReplyInlineMarkup::remove(user_id, message_id)
+                    .edit(bot_token, client)
+                    .await?;
+
+                let current_room = UserRoom::room_id(user_id as i32, redis).await?;
+                let current = match (user_role, question) {
+                    (Some(role), Some(idx)) if current_room.as_ref() == Some(&self.room_id) => {
+                        QuestionMessage::get(&self.room_id, idx, redis)
+                            .await?
+                            .map(|x| (role, x))
+                    }
+                    _ => None,
+                };
 
-            let send_next_question_keys = match user_role {
-                Some(role) => CallbackData::set_value_for_role(
-                    &role.to_string(),
-                    &message_type,
-                    self.idx,
-                    &self.room_id,
-                    redis,
-                )?,
-                _ => false,
-            };
+                // buttons of an earlier question or version are replaced with the current ones
+                if let Some((role, question_message)) = current {
+                    let url = create_tg_url(bot_token, TgMethods::SEND_MESSAGE);
+                    question_message
+                        .deliver(user_id as i32, &self.room_id, role, redis, client, &url)
+                        .await?;
+                    return answer_callback_query(bot_token, client, id.to_string(), None).await;
+                }
 
-            let inline_keyboard = vec![OutgoingInlineKeyboardMessage::create_eval_keys(
-                self.typ,
-                Some(self.idx),
-                &self.room_id,
-            )];
-            let edited_keys = EditedReplyInlineMarkup {
-                chat_id: user_id,
-                message_id,
-                reply_markup: Some(InlineKeyboardMarkup { inline_keyboard }),
-            };
+                let text = Some(locale.text(Messages::CALLBACK_OUTDATED).to_string());
+                return answer_callback_query(bot_token, client, id.to_string(), text).await;
+            }
 
-            edited_keys.edit(bot_token, client).await?;
+            let message_type = self.match_type();
 
-            if send_next_question_keys {
-                Context::set_context(user_id as i32, Context::WAITING_FOR_ANSWER, redis)?;
+            let rating_update = match user_role {
+                Some(role) => {
+                    CallbackData::set_value_for_role(
+                        &role.to_string(),
+                        &message_type,
+                        self.idx,
+                        &self.room_id,
+                        redis,
+                    )
+                    .await?
+                }
+                _ => RatingUpdate::Ignored,
+            };
 
-                let is_ready_for_next_msg = OutgoingKeyboardMessage {
-                    chat_id: user_id as i32,
-                    text: String::from(Messages::READY_FOR_NEXT),
-                    reply_markup: Some(ReplyKeyboardMarkup {
-                        keyboard: vec![vec![Keys::READY.to_string()]],
-                        one_time_keyboard: false,
-                    }),
-                    parse_mode: None,
-                };
+            if rating_update == RatingUpdate::Locked {
+                let text = Some(locale.text(Messages::GUESS_ALREADY_SET).to_string());
+                return answer_callback_query(bot_token, client, id.to_string(), text).await;
+            }
 
-                send_message(
-                    &create_tg_url(bot_token, TgMethods::SEND_MESSAGE),
-                    &is_ready_for_next_msg,
+            if let Some(role) = user_role {
+                let edit_url = create_tg_url(bot_token, TgMethods::EDIT_MESSAGE_TEXT);
+                QuestionMessage::refresh(
+                    user_id as i32,
+                    &self.room_id,
+                    role,
+                    message_id,
+                    redis,
                     client,
+                    &edit_url,
                 )
                 .await?;
+
+                if rating_update == RatingUpdate::Completed {
+                    Context::set_context(user_id as i32, Context::WAITING_FOR_ANSWER, redis)
+                        .await?;
+
+                    let url = create_tg_url(bot_token, TgMethods::SEND_MESSAGE);
+                    QuestionMessage::show_progress(&self.room_id, role, redis, client, &url)
+                        .await?;
+                }
             }
         }
 
-        answer_callback_query(bot_token, client, id.to_string(), self.idx, self.typ).await?;
+        let text = self.answer_text(locale);
+        answer_callback_query(bot_token, client, id.to_string(), text).await?;
 
         Ok(())
     }
 }
 
+/// Payload of the buttons which move the user through the bot instead of rating a question.
+/// `value` is the position of the selected option, e.g. in `RoomMode::ALL` for `CREATE`,
+/// pack buttons carry the pack name instead, as positions change when packs are added.
+#[derive(Debug)]
+pub struct NavigationData {
+    pub(crate) nav: u8,
+    pub(crate) value: u8,
+    pub(crate) pack: Option<String>,
+}
+
+impl NavigationData {
+    pub const CREATE: u8 = 1;
+    pub const JOIN: u8 = 2;
+    pub const READY: u8 = 3;
+    pub const PACK: u8 = 4;
+    pub const PACK_LANGUAGE: u8 = 5;
+    pub const ORDER: u8 = 6;
+    pub const TIMER: u8 = 7;
+    pub const UNREACHABLE: u8 = 8;
+
+    /// Update the button stands for, buttons of a step the user has already left are ignored.
+    async fn update_type(
+        &self,
+        user_id: i32,
+        redis: &mut RedisConnection,
+    ) -> Result<UpdateType, redis::RedisError> {
+        let context = Context::get(user_id, redis).await?;
+        let value = self.value as usize;
+
+        let update_type = match self.nav {
+            NavigationData::CREATE => match RoomMode::ALL.get(value) {
+                Some(&RoomMode::GUESS) => UpdateType::CreateGuess,
+                Some(&RoomMode::SOLO) => UpdateType::CreateSolo,
+                Some(&RoomMode::ASYNC) => UpdateType::CreateAsync,
+                Some(_) => UpdateType::Create,
+                None => UpdateType::Error,
+            },
+            NavigationData::JOIN => UpdateType::JoinExisting,
+            NavigationData::READY if context == Context::WAITING_FOR_ANSWER => {
+                UpdateType::WaitingForOther
+            }
+            NavigationData::PACK if context == Context::SELECT_PACK => {
+                let pack = match &self.pack {
+                    Some(prefix) => Pack::by_button(prefix, redis).await?,
+                    None => None,
+                };
+                UpdateType::SelectPack(pack)
+            }
+            NavigationData::PACK_LANGUAGE if context == Context::SELECT_PACK_LANGUAGE => {
+                UpdateType::SelectPackLanguage(Locale::ALL.get(value).copied())
+            }
+            NavigationData::ORDER if context == Context::SELECT_ORDER => {
+                UpdateType::SelectOrder(QuestionOrder::ALL.get(value).copied())
+            }
+            NavigationData::TIMER if context == Context::SELECT_TIMER => {
+                UpdateType::NewRoom(QuestionTimer::options().get(value).copied())
+            }
+            NavigationData::UNREACHABLE if context == Context::PARTNER_UNREACHABLE => {
+                ternary!(value == 0, UpdateType::ContinueAlone, UpdateType::LeaveRoom)
+            }
+            _ => UpdateType::Other,
+        };
+
+        Ok(update_type)
+    }
+}
+
 #[derive(Deserialize, Debug)]
 pub struct TgUpdate {
     pub(crate) update_id: i32,
     pub(crate) message: Option<TgMessage>,
-    edited_message: Option<TgMessage>,
-    inline_query: Option<InlineQuery>,
-    callback_query: Option<CallbackQuery>,
+    pub(crate) callback_query: Option<CallbackQuery>,
 }
 
 impl TgUpdate {
-    pub(crate) fn handle_message_type(
+    /// The user who sent the message or pressed the button.
+    pub(crate) fn from(&self) -> Option<&TgUser> {
+        self.message
+            .as_ref()
+            .map(|x| &x.from)
+            .or_else(|| self.callback_query.as_ref().map(|x| &x.from))
+    }
+
+    pub(crate) async fn handle_message_type(
         &self,
-        redis: &mut redis::Connection,
+        redis: &mut RedisConnection,
     ) -> Result<UpdateType, redis::RedisError> {
         let user_id = self.message.as_ref().map(|x| x.from.id);
         let message_text = self.message.as_ref().and_then(|x| x.text.as_ref());
 
-        if self.callback_query.is_some() {
-            let query = self.callback_query.as_ref().unwrap();
+        if let Some(query) = &self.callback_query {
+            let payload = match &query.data {
+                Some(data) => CallbackPayload::decode(data, redis).await?,
+                None => CallbackPayload::Invalid,
+            };
+
+            let data = match payload {
+                CallbackPayload::Navigation(navigation) => {
+                    tracing::info!(?navigation, "Navigation");
+                    return navigation.update_type(query.from.id, redis).await;
+                }
+                CallbackPayload::Rating(data) => Some(data),
+                CallbackPayload::Invalid => {
+                    tracing::warn!(data = ?query.data, "Invalid callback data");
+                    return Ok(UpdateType::InvalidCallback);
+                }
+            };
+
             let callback_query_id = &query.id;
-            let data = query
-                .data
-                .as_ref()
-                .and_then(|x| serde_json::from_str::<CallbackData>(&x).ok());
             let message_id = query.message.as_ref().map(|x| x.message_id);
             let chat_id = query.message.as_ref().map(|x| x.chat.id);
 
-            log::info!("{:?}, {:?}, {:?}", chat_id, message_id, data);
+            tracing::info!(?chat_id, ?message_id, ?data, "Callback");
 
             match (chat_id, message_id, data) {
                 (Some(uid), Some(cid), Some(d)) => Ok(UpdateType::Callback(
@@ -445,23 +900,55 @@ impl TgUpdate {
             == Some(true)
         {
             // using unsafe unwrap – user id or message cannot be empty in the bot api
-            TgUpdate::handle_bot_command(user_id.unwrap(), message_text.unwrap(), redis)
-        } else if message_text == Some(&Keys::JOIN.to_string()) {
+            TgUpdate::handle_bot_command(user_id.unwrap(), message_text.unwrap(), redis).await
+        } else if Keys::matches(message_text, Keys::JOIN) {
             Ok(UpdateType::JoinExisting)
-        } else if message_text == Some(&Keys::CREATE.to_string()) {
+        } else if Keys::matches(message_text, Keys::CREATE) {
             Ok(UpdateType::Create)
+        } else if Keys::matches(message_text, Keys::CREATE_GUESS) {
+            Ok(UpdateType::CreateGuess)
+        } else if Keys::matches(message_text, Keys::CREATE_SOLO) {
+            Ok(UpdateType::CreateSolo)
+        } else if Keys::matches(message_text, Keys::CREATE_ASYNC) {
+            Ok(UpdateType::CreateAsync)
         } else {
-            if user_id.is_some() {
-                let context_str = Context::get(user_id.unwrap(), redis)?;
+            if let Some(user_id) = user_id {
+                let context_str = Context::get(user_id, redis).await?;
 
+                // typed answers are only a fallback for the buttons
                 if context_str == Context::SELECT_PACK {
-                    Ok(UpdateType::NewRoom)
+                    Ok(UpdateType::SelectPack(message_text.cloned()))
+                } else if context_str == Context::SELECT_PACK_LANGUAGE {
+                    Ok(UpdateType::SelectPackLanguage(
+                        message_text.and_then(|x| Locale::from_name(x)),
+                    ))
+                } else if context_str == Context::SELECT_ORDER {
+                    Ok(UpdateType::SelectOrder(
+                        message_text.and_then(|x| QuestionOrder::from_key(x)),
+                    ))
+                } else if context_str == Context::SELECT_TIMER {
+                    Ok(UpdateType::NewRoom(
+                        message_text.and_then(|x| QuestionTimer::from_key(x)),
+                    ))
                 } else if context_str == Context::INSERT_ID {
                     Ok(UpdateType::InsertId)
                 } else if context_str == Context::WAITING_FOR_ANSWER
-                    && message_text == Some(&Keys::READY.to_string())
+                    && Keys::matches(message_text, Keys::READY)
                 {
                     Ok(UpdateType::WaitingForOther)
+                } else if (context_str == Context::IN_ROOM
+                    || context_str == Context::WAITING_FOR_ANSWER)
+                    && message_text.is_some()
+                {
+                    Ok(UpdateType::Note)
+                } else if context_str == Context::PARTNER_UNREACHABLE
+                    && Keys::matches(message_text, Keys::CONTINUE_ALONE)
+                {
+                    Ok(UpdateType::ContinueAlone)
+                } else if context_str == Context::PARTNER_UNREACHABLE
+                    && Keys::matches(message_text, Keys::LEAVE_ROOM)
+                {
+                    Ok(UpdateType::LeaveRoom)
                 } else if context_str == Context::WAITING_FOR_RESULTS {
                     Ok(UpdateType::WaitingForResults)
                 } else {
@@ -473,19 +960,23 @@ impl TgUpdate {
         }
     }
 
-    fn handle_bot_command(
+    async fn handle_bot_command(
         user_id: i32,
-        message_text: &String,
-        redis: &mut redis::Connection,
+        message_text: &str,
+        redis: &mut RedisConnection,
     ) -> Result<UpdateType, redis::RedisError> {
         if message_text.starts_with("/start") {
-            Context::reset(user_id, redis)?;
-            let room_key = Room::key_user(user_id, redis)?;
-            redis.del(room_key)?;
+            Context::reset(user_id, redis).await?;
+            let room_key = Room::key_user(user_id, redis).await?;
+            let _: () = redis.del(room_key).await?;
 
             Ok(UpdateType::Start)
         } else if message_text.starts_with("/help") {
             Ok(UpdateType::Help)
+        } else if message_text.starts_with("/compare") {
+            Ok(UpdateType::Compare)
+        } else if message_text.starts_with("/language") {
+            Ok(UpdateType::Language)
         } else {
             Ok(UpdateType::UnknownCommand)
         }
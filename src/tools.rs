@@ -29,6 +29,11 @@ pub fn get_parse_string_value<T: FromStr>(
     hm.get(key).and_then(|x| x.parse().ok()).unwrap_or(default)
 }
 
+/// Escapes a value for a single-quoted ClickHouse string literal.
+pub fn escape_string(value: &str) -> String {
+    value.replace('\\', "\\\\").replace('\'', "\\'")
+}
+
 pub fn current_time() -> u64 {
     SystemTime::now()
         .duration_since(SystemTime::UNIX_EPOCH)